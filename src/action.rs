@@ -1,16 +1,53 @@
 //! Filesystem operations to stage files.
 
+use std::cell::RefCell;
 use std::fmt;
 use std::fs;
 use std::path;
+use std::rc::Rc;
 
+use cache::Manifest;
 use error;
+use Staging;
 
 // `Display` is required for dry-runs / previews.
 /// Operation for setting up staged directory tree.
 pub trait FsAction: fmt::Display + fmt::Debug {
     /// Execute the current action, writing to the stage.
     fn perform(&self) -> Result<(), error::StagingError>;
+
+    /// The source path this action reads from, when it has one.
+    ///
+    /// Used by the watch subsystem to re-apply only the actions whose source changed.
+    fn source(&self) -> Option<&path::Path> {
+        None
+    }
+
+    /// The staged path this action produces.
+    ///
+    /// Used by the ordering pass to key the dependency graph by destination.
+    fn staged(&self) -> &path::Path;
+
+    /// Whether this action produces a directory that may contain other actions' outputs.
+    ///
+    /// Directories are containers rather than exclusive writes, so two actions creating the same
+    /// directory are not a conflict.
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    /// For a symlink, the in-stage path it points at and therefore depends on.
+    fn link_target(&self) -> Option<&path::Path> {
+        None
+    }
+
+    /// Whether this action modifies an already-staged path rather than producing it.
+    ///
+    /// A modifier (e.g. setting permissions) shares its destination with the action that produced
+    /// the file, so it is not a conflicting write and must be ordered after that producer.
+    fn is_modifier(&self) -> bool {
+        false
+    }
 }
 
 /// Specifies a staged directory to be created.
@@ -51,6 +88,14 @@ impl FsAction for CreateDirectory {
 
         Ok(())
     }
+
+    fn staged(&self) -> &path::Path {
+        &self.staged
+    }
+
+    fn is_dir(&self) -> bool {
+        true
+    }
 }
 
 /// Specifies a file to be staged into the target directory.
@@ -58,6 +103,7 @@ impl FsAction for CreateDirectory {
 pub struct CopyFile {
     staged: path::PathBuf,
     source: path::PathBuf,
+    cache: Option<Rc<RefCell<Manifest>>>,
 }
 
 impl CopyFile {
@@ -73,9 +119,16 @@ impl CopyFile {
         Self {
             staged: staged.into(),
             source: source.into(),
+            cache: None,
         }
     }
 
+    /// Share a manifest cache so repeated stages skip files that have not changed.
+    pub fn cached(mut self, manifest: Rc<RefCell<Manifest>>) -> Self {
+        self.cache = Some(manifest);
+        self
+    }
+
     /// The file to be copied.
     pub fn source(&self) -> &path::Path {
         &self.source
@@ -95,18 +148,40 @@ impl fmt::Display for CopyFile {
 
 impl FsAction for CopyFile {
     fn perform(&self) -> Result<(), error::StagingError> {
+        if let Some(ref cache) = self.cache {
+            if cache.borrow().is_current(&self.staged, &self.source) {
+                return Ok(());
+            }
+        }
         if let Some(parent) = self.staged.parent() {
             fs::create_dir_all(parent)
                 .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
         }
         fs::copy(&self.source, &self.staged)
             .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        if let Some(ref cache) = self.cache {
+            cache
+                .borrow_mut()
+                .record(&self.staged, &self.source)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
 
         Ok(())
     }
+
+    fn source(&self) -> Option<&path::Path> {
+        Some(&self.source)
+    }
+
+    fn staged(&self) -> &path::Path {
+        &self.staged
+    }
 }
 
 /// Specifies a symbolic link file to be staged into the target directory.
+///
+/// The link target is written verbatim; keeping it inside the stage is the responsibility of the
+/// builder or configuration that constructs the action (see [`contain::contained`](::contain::contained)).
 #[derive(Clone, Debug)]
 pub struct Symlink {
     staged: path::PathBuf,
@@ -158,4 +233,145 @@ impl FsAction for Symlink {
 
         Ok(())
     }
+
+    fn staged(&self) -> &path::Path {
+        &self.staged
+    }
+
+    fn link_target(&self) -> Option<&path::Path> {
+        Some(&self.target)
+    }
+}
+
+/// Specifies a permission mode to be applied to a staged file.
+#[derive(Clone, Debug)]
+pub struct SetPermissions {
+    staged: path::PathBuf,
+    mode: u32,
+}
+
+impl SetPermissions {
+    /// Specifies a permission mode to be applied to a staged file.
+    ///
+    /// - `staged`: full path to the file whose mode will be set.
+    /// - `mode`: the Unix permission bits (e.g. `0o755`).
+    pub fn new<P>(staged: P, mode: u32) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            mode,
+        }
+    }
+
+    /// The file whose mode will be set.
+    pub fn destination(&self) -> &path::Path {
+        &self.staged
+    }
+
+    /// The mode that will be applied.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+impl fmt::Display for SetPermissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "chmod {:o} {:?}", self.mode, self.staged)
+    }
+}
+
+impl FsAction for SetPermissions {
+    #[cfg(not(target_os = "windows"))]
+    fn perform(&self) -> Result<(), error::StagingError> {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = fs::Permissions::from_mode(self.mode);
+        fs::set_permissions(&self.staged, permissions)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn perform(&self) -> Result<(), error::StagingError> {
+        // Unix permission bits have no meaning on Windows.
+        Ok(())
+    }
+
+    fn staged(&self) -> &path::Path {
+        &self.staged
+    }
+
+    fn is_modifier(&self) -> bool {
+        true
+    }
+}
+
+/// A `Staging` executor that records the ordered `FsAction`s instead of performing them.
+///
+/// This powers a dry-run / preview mode: a caller stages into a `Planner` and then prints the
+/// collected actions via their `Display` impls to show exactly what would be written.
+#[derive(Debug, Default)]
+pub struct Planner {
+    actions: Vec<Box<FsAction>>,
+}
+
+impl Planner {
+    /// An empty plan.
+    pub fn new() -> Self {
+        Self {
+            actions: Vec::new(),
+        }
+    }
+
+    /// The ordered actions that would be performed.
+    pub fn plan(self) -> Vec<Box<FsAction>> {
+        self.actions
+    }
+}
+
+impl Staging for Planner {
+    fn directory(&mut self, path: &path::Path) -> Result<(), error::StagingError> {
+        self.actions.push(Box::new(CreateDirectory::new(path)));
+        Ok(())
+    }
+
+    fn file_from_path(
+        &mut self,
+        dest: &path::Path,
+        src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.actions.push(Box::new(CopyFile::new(dest, src)));
+        Ok(())
+    }
+
+    fn file_from_reader(
+        &mut self,
+        dest: &path::Path,
+        _src: &mut ::std::io::Read,
+    ) -> Result<(), error::StagingError> {
+        // The stream has no path to record; the destination alone describes the planned write.
+        self.actions
+            .push(Box::new(CopyFile::new(dest, path::PathBuf::new())));
+        Ok(())
+    }
+
+    fn symlink_dir(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.actions.push(Box::new(Symlink::new(path, target)));
+        Ok(())
+    }
+
+    fn symlink_file(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.actions.push(Box::new(Symlink::new(path, target)));
+        Ok(())
+    }
 }
@@ -12,16 +12,25 @@
 //! let stage = stage.build(target).unwrap();
 //! ```
 
+use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::ffi;
 use std::fmt;
 use std::iter;
 use std::path;
+use std::rc::Rc;
 
-use globwalk;
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
 
 use action;
+use cache;
+use contain;
 use error;
+use graph;
+
+/// Name of the manifest persisted inside a stage to enable incremental re-staging.
+const MANIFEST_FILE: &str = ".stager-manifest.json";
 
 /// Create concrete filesystem actions.
 pub trait ActionBuilder: fmt::Debug {
@@ -30,14 +39,77 @@ pub trait ActionBuilder: fmt::Debug {
     /// Create concrete filesystem actions.
     ///
     /// - `target_dir`: The location everything will be written to (ie the stage).
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors>;
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors>;
+
+    /// Create concrete filesystem actions that share a manifest `cache`.
+    ///
+    /// File copies produced by this builder are wired to `cache` so an unchanged source is left
+    /// untouched on a subsequent run.  The default implementation ignores the cache and falls back
+    /// to [`build`](ActionBuilder::build).
+    fn build_cached(
+        &self,
+        target_dir: &path::Path,
+        cache: &Rc<RefCell<cache::Manifest>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        let _ = cache;
+        self.build(target_dir)
+    }
+
+    /// The source roots this builder reads from.
+    ///
+    /// Used to scope a filesystem watch to the paths that actually feed the stage.
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        Vec::new()
+    }
 }
 
 impl<A: ActionBuilder + ?Sized> ActionBuilder for Box<A> {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
         let target: &A = &self;
         target.build(target_dir)
     }
+
+    fn build_cached(
+        &self,
+        target_dir: &path::Path,
+        cache: &Rc<RefCell<cache::Manifest>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        let target: &A = &self;
+        target.build_cached(target_dir, cache)
+    }
+
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        let target: &A = &self;
+        target.source_roots()
+    }
+}
+
+/// Build `builder`'s actions and perform them against `target_dir`, skipping files that are
+/// byte-identical to the previous run.
+///
+/// A [`cache::Manifest`] persisted at `<target_dir>/.stager-manifest.json` records each staged
+/// file's source stamp; it is reloaded before staging and rewritten afterward, so a subsequent
+/// run re-copies only what changed.
+pub fn stage<A: ActionBuilder>(
+    builder: &A,
+    target_dir: &path::Path,
+) -> Result<(), error::Errors> {
+    let manifest_path = target_dir.join(MANIFEST_FILE);
+    let manifest = cache::Manifest::load(&manifest_path)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    let manifest = Rc::new(RefCell::new(manifest));
+
+    let actions = builder.build_cached(target_dir, &manifest)?;
+    for action in &actions {
+        action.perform()?;
+    }
+
+    manifest
+        .borrow()
+        .save(&manifest_path)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+    Ok(())
 }
 
 /// For each stage target, a list of sources to populate it with.
@@ -46,37 +118,62 @@ impl<A: ActionBuilder + ?Sized> ActionBuilder for Box<A> {
 #[derive(Default, Debug)]
 pub struct Stage(BTreeMap<path::PathBuf, Vec<Box<ActionBuilder>>>);
 
-impl ActionBuilder for Stage {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let staging: Result<Vec<_>, _> = self.0
-            .iter()
-            .map(|(target, sources)| {
-                if target.is_absolute() {
-                    let mut errors = error::Errors::new();
-                    errors.push(
-                        error::StagingError::new(error::ErrorKind::HarvestingFailed).set_context(
-                            format!("target must be relative to the stage root: {:?}", target),
-                        ),
-                    );
-                    return errors;
-                }
-                let target = target_dir.join(target);
-                let mut errors = error::Errors::new();
-                let sources = {
-                    let sources = sources.into_iter().flat_map(|s| s.build(&target));
-                    let sources = error::ErrorPartition::new(sources, &mut errors);
-                    let sources: Vec<_> = sources.collect();
-                    sources
+impl Stage {
+    fn build_inner(
+        &self,
+        target_dir: &path::Path,
+        cache: Option<&Rc<RefCell<cache::Manifest>>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        let mut actions = Vec::new();
+        let mut errors: Vec<error::StagingError> = Vec::new();
+        for (target, sources) in &self.0 {
+            if target.is_absolute() {
+                errors.push(error::ErrorKind::HarvestingFailed.error().set_context(
+                    format!("target must be relative to the stage root: {:?}", target),
+                ));
+                continue;
+            }
+            let target = target_dir.join(target);
+            for source in sources {
+                let built = match cache {
+                    Some(cache) => source.build_cached(&target, cache),
+                    None => source.build(&target),
                 };
-                errors.ok(sources)
-            })
-            .collect();
-        let staging = staging?;
-        let staging: Vec<_> = staging
-            .into_iter()
-            .flat_map(|v| v.into_iter().flat_map(|v: Vec<_>| v.into_iter()))
-            .collect();
-        Ok(staging)
+                match built {
+                    Ok(sub) => actions.extend(sub),
+                    Err(sub) => errors.extend(sub),
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.into_iter().collect());
+        }
+        // Order the accumulated actions so directories precede their contents and symlinks follow
+        // the in-stage targets they point at, regardless of the order the sources were declared in.
+        let actions = graph::order(actions)?;
+
+        Ok(actions)
+    }
+}
+
+impl ActionBuilder for Stage {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, None)
+    }
+
+    fn build_cached(
+        &self,
+        target_dir: &path::Path,
+        cache: &Rc<RefCell<cache::Manifest>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, Some(cache))
+    }
+
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        self.0
+            .values()
+            .flat_map(|sources| sources.iter().flat_map(|s| s.source_roots()))
+            .collect()
     }
 }
 
@@ -96,6 +193,7 @@ pub struct SourceFile {
     path: path::PathBuf,
     rename: Option<String>,
     symlink: Vec<String>,
+    mode: Option<u32>,
 }
 
 impl SourceFile {
@@ -110,6 +208,7 @@ impl SourceFile {
             path: source.into(),
             rename: None,
             symlink: Default::default(),
+            mode: None,
         }
     }
 
@@ -125,14 +224,27 @@ impl SourceFile {
         self.symlink.extend(symlinks);
         self
     }
+
+    /// Specifies the Unix permission bits (e.g. `0o755`) to apply to the staged file.  Default is
+    /// to leave the copied file's mode untouched.
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
 }
 
-impl ActionBuilder for SourceFile {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+impl SourceFile {
+    fn build_inner(
+        &self,
+        target_dir: &path::Path,
+        cache: Option<&Rc<RefCell<cache::Manifest>>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
         let path = self.path.as_path();
         if !path.is_absolute() {
-            return error::StagingError::new(error::ErrorKind::HarvestingFailed)
-                .set_context(format!("SourceFile path must be absolute: {:?}", path));
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!("SourceFile path must be absolute: {:?}", path))
+                .into());
         }
 
         let filename = self.rename
@@ -141,15 +253,20 @@ impl ActionBuilder for SourceFile {
             .unwrap_or_else(|| path.file_name().unwrap_or_default());
         let filename = path::Path::new(filename);
         if filename.file_name() != Some(filename.as_os_str()) {
-            return error::StagingError::new(error::ErrorKind::HarvestingFailed).set_context(
-                format!(
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
                     "SourceFile rename must not change directories: {:?}",
                     filename
-                ),
-            );
+                ))
+                .into());
         }
         let copy_target = target_dir.join(filename);
-        let copy: Box<action::Action> = Box::new(action::CopyFile::new(&copy_target, path));
+        let mut copy = action::CopyFile::new(&copy_target, path);
+        if let Some(cache) = cache {
+            copy = copy.cached(cache.clone());
+        }
+        let copy: Box<action::FsAction> = Box::new(copy);
 
         let mut actions = vec![copy];
         actions.extend(self.symlink.iter().map(|s| {
@@ -159,15 +276,36 @@ impl ActionBuilder for SourceFile {
             //    bail!("SourceFile symlink must not change directories: {:?}", s);
             //}
             let sym_target = target_dir.join(s);
-            let a: Box<action::Action> = Box::new(action::Symlink::new(sym_target, &copy_target));
+            let a: Box<action::FsAction> = Box::new(action::Symlink::new(sym_target, &copy_target));
             a
         }));
         // TODO(epage): Set symlink permissions
+        if let Some(mode) = self.mode {
+            actions.push(Box::new(action::SetPermissions::new(&copy_target, mode)));
+        }
 
         Ok(actions)
     }
 }
 
+impl ActionBuilder for SourceFile {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, None)
+    }
+
+    fn build_cached(
+        &self,
+        target_dir: &path::Path,
+        cache: &Rc<RefCell<cache::Manifest>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, Some(cache))
+    }
+
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
 /// Specifies a collection of files to be staged into the target directory.
 #[derive(Clone, Debug)]
 pub struct SourceFiles {
@@ -222,28 +360,61 @@ impl SourceFiles {
     }
 }
 
-impl ActionBuilder for SourceFiles {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let mut actions: Vec<Box<action::Action>> = Vec::new();
+impl SourceFiles {
+    fn build_inner(
+        &self,
+        target_dir: &path::Path,
+        cache: Option<&Rc<RefCell<cache::Manifest>>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
         let source_root = self.path.as_path();
         if !source_root.is_absolute() {
-            return error::StagingError::new(error::ErrorKind::HarvestingFailed).set_context(
-                format!("SourceFiles path must be absolute: {:?}", source_root),
-            );
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "SourceFiles path must be absolute: {:?}",
+                    source_root
+                ))
+                .into());
         }
-        for entry in globwalk::GlobWalker::from_patterns(source_root, &self.pattern)?
-            .follow_links(self.follow_links)
-        {
-            let entry = entry?;
+
+        let mut overrides = OverrideBuilder::new(source_root);
+        for pattern in &self.pattern {
+            overrides
+                .add(pattern)
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+        }
+        let overrides = overrides
+            .build()
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+
+        // The `ignore` walker honors nested `.gitignore`/`.ignore` files as it descends, so a
+        // source tree's own ignore rules are respected without the caller restating them.
+        let mut builder = WalkBuilder::new(source_root);
+        builder.follow_links(self.follow_links).overrides(overrides);
+
+        let mut sources: Vec<path::PathBuf> = Vec::new();
+        for entry in builder.build() {
+            let entry = entry.map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
             let source_file = entry.path();
             if source_file.is_dir() {
                 continue;
             }
-            let rel_source = source_file.strip_prefix(source_root)?;
+            sources.push(source_file.to_owned());
+        }
+        // Stage in a deterministic order so the resulting tree is reproducible.
+        sources.sort();
+
+        let mut actions: Vec<Box<action::FsAction>> = Vec::new();
+        for source_file in &sources {
+            let rel_source = source_file
+                .strip_prefix(source_root)
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
             let copy_target = target_dir.join(rel_source);
-            let copy: Box<action::Action> =
-                Box::new(action::CopyFile::new(&copy_target, source_file));
-            actions.push(copy);
+            let mut copy = action::CopyFile::new(&copy_target, source_file);
+            if let Some(cache) = cache {
+                copy = copy.cached(cache.clone());
+            }
+            actions.push(Box::new(copy) as Box<action::FsAction>);
         }
 
         if actions.is_empty() {
@@ -253,12 +424,13 @@ impl ActionBuilder for SourceFiles {
                     self.path, self.pattern
                 );
             } else {
-                return error::StagingError::new(error::ErrorKind::HarvestingFailed).set_context(
-                    format!(
+                return Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
                         "No files found under {:?} with patterns {:?}",
                         self.path, self.pattern
-                    ),
-                );
+                    ))
+                    .into());
             }
         }
 
@@ -266,6 +438,24 @@ impl ActionBuilder for SourceFiles {
     }
 }
 
+impl ActionBuilder for SourceFiles {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, None)
+    }
+
+    fn build_cached(
+        &self,
+        target_dir: &path::Path,
+        cache: &Rc<RefCell<cache::Manifest>>,
+    ) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        self.build_inner(target_dir, Some(cache))
+    }
+
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
 /// Specifies a symbolic link file to be staged into the target directory.
 #[derive(Clone, Debug)]
 pub struct Symlink {
@@ -296,8 +486,12 @@ impl Symlink {
 }
 
 impl ActionBuilder for Symlink {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let target = self.target.as_path();
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::FsAction>>, error::Errors> {
+        // Keep the target inside the stage: a leading `/` is treated as the stage root and `..` is
+        // resolved, so an absolute target such as `/etc/passwd` is rewritten stage-relative and a
+        // target that climbs above the root is rejected rather than followed out of the tree.
+        let target = contain::contained(&self.target)?;
+        let target = target.as_path();
 
         let filename = self.rename
             .as_ref()
@@ -305,15 +499,63 @@ impl ActionBuilder for Symlink {
             .unwrap_or_else(|| target.file_name().unwrap_or_default());
         let filename = path::Path::new(filename);
         if filename.file_name() != Some(filename.as_os_str()) {
-            return error::StagingError::new(error::ErrorKind::HarvestingFailed).set_context(
-                format!("Symlink rename must not change directories: {:?}", filename),
-            );
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "Symlink rename must not change directories: {:?}",
+                    filename
+                ))
+                .into());
         }
         let staged = target_dir.join(filename);
-        let link: Box<action::Action> = Box::new(action::Symlink::new(&staged, target));
+        let link: Box<action::FsAction> = Box::new(action::Symlink::new(&staged, target));
 
         let actions = vec![link];
 
         Ok(actions)
     }
+
+    fn source_roots(&self) -> Vec<path::PathBuf> {
+        vec![self.target.clone()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    fn staged(actions: &[Box<action::FsAction>]) -> Vec<path::PathBuf> {
+        actions.iter().map(|a| a.staged().to_owned()).collect()
+    }
+
+    #[test]
+    fn source_files_honors_ignore_files_and_sorts() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("src");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), b"b").unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(root.join("skip.log"), b"x").unwrap();
+        fs::write(root.join(".ignore"), b"skip.log\n").unwrap();
+        fs::write(root.join("sub").join("c.txt"), b"c").unwrap();
+
+        let actions = SourceFiles::new(&root)
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let staged = staged(&actions);
+
+        assert_eq!(
+            staged,
+            vec![
+                path::PathBuf::from("/stage/a.txt"),
+                path::PathBuf::from("/stage/b.txt"),
+                path::PathBuf::from("/stage/sub/c.txt"),
+            ],
+            "ignored and hidden files are dropped and the rest are sorted"
+        );
+    }
 }
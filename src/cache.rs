@@ -0,0 +1,181 @@
+//! Manifest-backed cache to skip re-copying unchanged files.
+//!
+//! A [`Manifest`] records a [`FileStamp`] for each staged destination.  On a subsequent stage, a
+//! destination whose source matches the recorded stamp is left untouched; anything missing,
+//! corrupt, or changed is rewritten, so a skipped copy is always byte-identical to a real one.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::path;
+
+use filetime::FileTime;
+
+/// The recorded state of a source file at the time it was staged.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    seconds: i64,
+    nanos: u32,
+    len: u64,
+    hash: u64,
+}
+
+/// Maps a staged destination to the [`FileStamp`] of the source it was copied from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest(BTreeMap<path::PathBuf, FileStamp>);
+
+impl Manifest {
+    /// An empty manifest.
+    pub fn new() -> Self {
+        Manifest(BTreeMap::new())
+    }
+
+    /// Load a manifest from `path`, returning an empty manifest if it does not yet exist.
+    pub fn load(path: &path::Path) -> io::Result<Self> {
+        match fs::File::open(path) {
+            Ok(f) => serde_json::from_reader(f)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persist the manifest to `path`.
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        let f = fs::File::create(path)?;
+        serde_json::to_writer(f, self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Whether `dest` is already an up-to-date copy of `source`.
+    ///
+    /// A missing or altered destination, an unknown source, or any IO error forces a rewrite.
+    pub fn is_current(&self, dest: &path::Path, source: &path::Path) -> bool {
+        let stamp = match self.0.get(dest) {
+            Some(stamp) => stamp,
+            None => return false,
+        };
+        // The destination must still hold exactly what was recorded, so a missing or corrupt copy
+        // is rewritten.
+        match hash_file(dest) {
+            Ok(hash) if hash == stamp.hash => {}
+            _ => return false,
+        }
+        let meta = match fs::metadata(source) {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        if meta.len() != stamp.len {
+            return false;
+        }
+        let mtime = FileTime::from_last_modification_time(&meta);
+        if mtime.seconds() == stamp.seconds && mtime.nanoseconds() == stamp.nanos {
+            return true;
+        }
+        // The mtime moved but the length matches; compare contents before rewriting.
+        match hash_file(source) {
+            Ok(hash) => hash == stamp.hash,
+            Err(_) => false,
+        }
+    }
+
+    /// Record that `dest` now holds a copy of `source`.
+    pub fn record(&mut self, dest: &path::Path, source: &path::Path) -> io::Result<()> {
+        let stamp = stamp_for(source)?;
+        self.0.insert(dest.to_owned(), stamp);
+        Ok(())
+    }
+}
+
+fn stamp_for(source: &path::Path) -> io::Result<FileStamp> {
+    let meta = fs::metadata(source)?;
+    let mtime = FileTime::from_last_modification_time(&meta);
+    Ok(FileStamp {
+        seconds: mtime.seconds(),
+        nanos: mtime.nanoseconds(),
+        len: meta.len(),
+        hash: hash_file(source)?,
+    })
+}
+
+fn hash_file(source: &path::Path) -> io::Result<u64> {
+    use std::collections::hash_map::DefaultHasher;
+
+    let mut f = fs::File::open(source)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0u8; 8 * 1024];
+    loop {
+        let read = f.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buf[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn is_current_true_for_recorded_unchanged_source() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        fs::write(&source, b"hello").unwrap();
+        fs::write(&dest, b"hello").unwrap();
+
+        let mut manifest = Manifest::new();
+        manifest.record(&dest, &source).unwrap();
+
+        assert!(manifest.is_current(&dest, &source));
+    }
+
+    #[test]
+    fn is_current_false_when_source_length_changes() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        fs::write(&source, b"hello").unwrap();
+        fs::write(&dest, b"hello").unwrap();
+
+        let mut manifest = Manifest::new();
+        manifest.record(&dest, &source).unwrap();
+        fs::write(&source, b"hello, world").unwrap();
+
+        assert!(!manifest.is_current(&dest, &source));
+    }
+
+    #[test]
+    fn is_current_false_for_unrecorded_dest() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        fs::write(&source, b"hello").unwrap();
+        fs::write(&dest, b"hello").unwrap();
+
+        let manifest = Manifest::new();
+        assert!(!manifest.is_current(&dest, &source));
+    }
+
+    #[test]
+    fn load_of_missing_manifest_is_empty_and_roundtrips() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        let manifest_path = dir.path().join("manifest.json");
+        fs::write(&source, b"hello").unwrap();
+        fs::write(&dest, b"hello").unwrap();
+
+        let mut manifest = Manifest::load(&manifest_path).unwrap();
+        manifest.record(&dest, &source).unwrap();
+        manifest.save(&manifest_path).unwrap();
+
+        let reloaded = Manifest::load(&manifest_path).unwrap();
+        assert!(reloaded.is_current(&dest, &source));
+    }
+}
@@ -0,0 +1,79 @@
+//! Keep resolved paths from escaping the stage root.
+//!
+//! This generalizes the `..`/`.`-normalization used by `de::abs_to_rel` into a reusable
+//! safe-join guardrail, applied to symlink targets and glob results so a configuration cannot
+//! silently point outside the stage.
+
+use std::path;
+
+use error;
+
+/// Normalize `path` against the stage root, resolving `.`/`..` components.
+///
+/// A leading root (`/`) is treated as the stage root, so an absolute `path` is deliberately
+/// rewritten to a stage-relative one (e.g. `/etc/hosts` becomes `etc/hosts`) rather than escaping
+/// to the real filesystem root.  A `..` that would climb above the root is rejected with an
+/// [`InvalidConfiguration`](error::ErrorKind::InvalidConfiguration) error.
+pub fn contained(path: &path::Path) -> Result<path::PathBuf, error::StagingError> {
+    use std::path::Component;
+
+    let mut normalized = path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(error::ErrorKind::InvalidConfiguration
+                        .error()
+                        .set_context(format!(
+                            "Path is outside of staging root: {}",
+                            path.display()
+                        )));
+                }
+            }
+            Component::Normal(part) => normalized.push(part),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Whether `path` stays within the stage root once normalized.
+pub fn is_contained(path: &path::Path) -> bool {
+    contained(path).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn contained_keeps_plain_paths() {
+        assert_eq!(
+            contained(path::Path::new("/hello/world")).unwrap(),
+            path::PathBuf::from("hello/world")
+        );
+    }
+
+    #[test]
+    fn contained_rewrites_absolute_into_stage() {
+        // An absolute target is kept inside the stage rather than escaping to the real root.
+        assert_eq!(
+            contained(path::Path::new("/etc/hosts")).unwrap(),
+            path::PathBuf::from("etc/hosts")
+        );
+    }
+
+    #[test]
+    fn contained_rejects_escape() {
+        assert!(contained(path::Path::new("/../world")).is_err());
+        assert!(contained(path::Path::new("hello/../../world")).is_err());
+    }
+
+    #[test]
+    fn contained_cleans_internal_ups() {
+        assert_eq!(
+            contained(path::Path::new("/hello/../goodbye/world")).unwrap(),
+            path::PathBuf::from("goodbye/world")
+        );
+    }
+}
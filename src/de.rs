@@ -29,8 +29,10 @@
 
 use std::collections::BTreeMap;
 use std::path;
+use std::rc::Rc;
 
 use super::Staging;
+use contain;
 use error;
 use spec;
 
@@ -53,7 +55,10 @@ pub type MapStage = CustomMapStage<Source>;
 /// The target is an absolute path, treating the stage as the root.  The target supports template
 /// formatting.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CustomMapStage<R: RenderSpecification>(BTreeMap<Template, Vec<R>>);
+pub struct CustomMapStage<R: RenderSpecification> {
+    #[serde(flatten)]
+    stage: BTreeMap<Template, Vec<R>>,
+}
 
 impl<R: RenderSpecification> CustomMapStage<R> {
     pub fn format(&self, engine: &TemplateEngine) -> Result<(), error::Errors> {
@@ -64,7 +69,7 @@ impl<R: RenderSpecification> CustomMapStage<R> {
 impl<R: RenderSpecification> Default for CustomMapStage<R> {
     fn default() -> Self {
         Self {
-            0: Default::default(),
+            stage: Default::default(),
         }
     }
 }
@@ -77,6 +82,10 @@ pub enum Source {
     SourceFile(SourceFile),
     /// Specifies a collection of files to be staged into the target directory.
     SourceFiles(SourceFiles),
+    /// Specifies a file whose contents are rendered through the template engine while staging.
+    TemplateFile(TemplateFile),
+    /// Specifies a file whose `{name}`/`[name]` placeholders are substituted while staging.
+    SubstituteFile(SubstituteFile),
     /// Specifies a symbolic link file to be staged into the target directory.
     Symlink(Symlink),
     #[doc(hidden)]
@@ -89,6 +98,12 @@ impl Source {
         let value = match *self {
             Source::SourceFile(ref b) => SpecificationBuilderInner::SourceFile(b.format(engine)?),
             Source::SourceFiles(ref b) => SpecificationBuilderInner::SourceFiles(b.format(engine)?),
+            Source::TemplateFile(ref b) => {
+                SpecificationBuilderInner::TemplateFile(b.format(engine)?)
+            }
+            Source::SubstituteFile(ref b) => {
+                SpecificationBuilderInner::SubstituteFile(b.format(engine)?)
+            }
             Source::Symlink(ref b) => SpecificationBuilderInner::Symlink(b.format(engine)?),
             Source::__Nonexhaustive => unreachable!("This is a non-public case"),
         };
@@ -101,6 +116,8 @@ impl RenderSpecification for Source {
         let value = match *self {
             Source::SourceFile(ref b) => RenderSpecification::format(b, engine)?,
             Source::SourceFiles(ref b) => RenderSpecification::format(b, engine)?,
+            Source::TemplateFile(ref b) => RenderSpecification::format(b, engine)?,
+            Source::SubstituteFile(ref b) => RenderSpecification::format(b, engine)?,
             Source::Symlink(ref b) => RenderSpecification::format(b, engine)?,
             Source::__Nonexhaustive => unreachable!("This is a non-public case"),
         };
@@ -108,7 +125,7 @@ impl RenderSpecification for Source {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 /// Create concrete filesystem specs.
 pub struct SpecificationBuilder(SpecificationBuilderInner);
 
@@ -128,10 +145,12 @@ impl spec::SpecificationBuilder for SpecificationBuilder {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 enum SpecificationBuilderInner {
     SourceFile(spec::SourceFileBuilder),
     SourceFiles(spec::SourceFilesBuilder),
+    TemplateFile(spec::RenderFileBuilder),
+    SubstituteFile(spec::TemplateFileBuilder),
     Symlink(spec::SymlinkBuilder),
     __Nonexhaustive,
 }
@@ -141,6 +160,8 @@ impl SpecificationBuilderInner {
         let value = match self {
             SpecificationBuilderInner::SourceFile(b) => SpecificationInner::SourceFile(b.resolve(target_dir)?),
             SpecificationBuilderInner::SourceFiles(b) => SpecificationInner::SourceFiles(b.resolve(target_dir)?),
+            SpecificationBuilderInner::TemplateFile(b) => SpecificationInner::TemplateFile(b.resolve(target_dir)?),
+            SpecificationBuilderInner::SubstituteFile(b) => SpecificationInner::SubstituteFile(b.resolve(target_dir)?),
             SpecificationBuilderInner::Symlink(b) => SpecificationInner::Symlink(b.resolve(target_dir)?),
             SpecificationBuilderInner::__Nonexhaustive => unreachable!("This is a non-public case"),
         };
@@ -148,7 +169,7 @@ impl SpecificationBuilderInner {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 /// Concrete filesystem specs.
 pub struct Specification(SpecificationInner);
 
@@ -158,10 +179,12 @@ impl spec::Specification for Specification {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 enum SpecificationInner {
     SourceFile(spec::SourceFile),
     SourceFiles(spec::SourceFiles),
+    TemplateFile(spec::RenderFile),
+    SubstituteFile(spec::TemplateFile),
     Symlink(spec::Symlink),
     __Nonexhaustive,
 }
@@ -171,6 +194,8 @@ impl spec::Specification for SpecificationInner {
         match self {
             SpecificationInner::SourceFile(b) => b.stage(stage),
             SpecificationInner::SourceFiles(b) => b.stage(stage),
+            SpecificationInner::TemplateFile(b) => b.stage(stage),
+            SpecificationInner::SubstituteFile(b) => b.stage(stage),
             SpecificationInner::Symlink(b) => b.stage(stage),
             SpecificationInner::__Nonexhaustive => unreachable!("This is a non-public case"),
         }
@@ -190,6 +215,10 @@ pub struct SourceFile {
     /// Specifies symbolic links to `rename` in the same target directory.
     #[serde(default)]
     pub symlink: Option<OneOrMany<Template>>,
+    /// Specifies the permission mode, as an octal string (e.g. `"0755"`), to apply to the staged
+    /// file.  Default is to leave the copied file's mode untouched.
+    #[serde(default)]
+    pub mode: Option<Template>,
     #[serde(skip)]
     non_exhaustive: (),
 }
@@ -206,9 +235,14 @@ impl SourceFile {
             .as_ref()
             .map(|t| t.format(engine))
             .map_or(Ok(None), |r| r.map(Some))?;
+        let mode = self.mode
+            .as_ref()
+            .map(|t| t.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?;
         let value = spec::SourceFileBuilder::new(path)
             .rename(rename)
-            .push_symlinks(symlink.into_iter());
+            .push_symlinks(symlink.into_iter())
+            .mode(mode);
         Ok(value)
     }
 }
@@ -242,6 +276,10 @@ pub struct SourceFiles {
     /// implements a lot of default "good enough" policy.
     #[serde(default)]
     pub allow_empty: bool,
+    /// Specifies the permission mode, as an octal string (e.g. `"0755"`), to apply to every staged
+    /// file.  Default is to leave each copied file's mode untouched.
+    #[serde(default)]
+    pub mode: Option<Template>,
     #[serde(skip)]
     non_exhaustive: (),
 }
@@ -250,10 +288,15 @@ impl SourceFiles {
     fn format(&self, engine: &TemplateEngine) -> Result<spec::SourceFilesBuilder, error::Errors> {
         let path = path::PathBuf::from(self.path.format(engine)?);
         let pattern = self.pattern.format(engine)?;
+        let mode = self.mode
+            .as_ref()
+            .map(|t| t.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?;
         let value = spec::SourceFilesBuilder::new(path)
             .push_patterns(pattern.into_iter())
             .follow_links(self.follow_links)
-            .allow_empty(self.allow_empty);
+            .allow_empty(self.allow_empty)
+            .mode(mode);
         Ok(value)
     }
 }
@@ -267,6 +310,113 @@ impl RenderSpecification for SourceFiles {
     }
 }
 
+/// Specifies a file whose contents are rendered through the template engine while staging.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateFile {
+    ///  Specifies the full path of the file to be rendered into the target directory
+    pub path: Template,
+    /// Specifies the name the target file should be renamed as when rendering from the source file.
+    /// Default is the filename of the source file.
+    #[serde(default)]
+    pub rename: Option<Template>,
+    /// Specifies symbolic links to `rename` in the same target directory.
+    #[serde(default)]
+    pub symlink: Option<OneOrMany<Template>>,
+    /// When true, a source that is not valid UTF-8 is copied unrendered rather than erroring.
+    #[serde(default)]
+    pub allow_binary: bool,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl TemplateFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<spec::RenderFileBuilder, error::Errors> {
+        let path = path::PathBuf::from(self.path.format(engine)?);
+        let symlink = self.symlink
+            .as_ref()
+            .map(|a| a.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?
+            .unwrap_or_default();
+        let rename = self.rename
+            .as_ref()
+            .map(|t| t.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let value = spec::RenderFileBuilder::new(path, Rc::new(engine.clone()))
+            .rename(rename)
+            .push_symlinks(symlink.into_iter())
+            .allow_binary(self.allow_binary);
+        Ok(value)
+    }
+}
+
+impl RenderSpecification for TemplateFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<Box<spec::SpecificationBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<spec::SpecificationBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a file whose `{name}`/`[name]` placeholders are substituted while staging.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubstituteFile {
+    ///  Specifies the full path of the file to be rendered into the target directory
+    pub path: Template,
+    /// Specifies the name the target file should be renamed as when copying from the source file.
+    /// Default is the filename of the source file.
+    #[serde(default)]
+    pub rename: Option<Template>,
+    /// Specifies symbolic links to `rename` in the same target directory.
+    #[serde(default)]
+    pub symlink: Option<OneOrMany<Template>>,
+    /// Placeholder name to value bindings substituted into the source file's contents.
+    #[serde(default)]
+    pub vars: BTreeMap<String, Template>,
+    /// When true, `[name]` is recognized as a placeholder in addition to `{name}`.  Default is
+    /// `false` so `[...]` in content such as TOML headers and Markdown links is left untouched.
+    #[serde(default)]
+    pub brackets: bool,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl SubstituteFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<spec::TemplateFileBuilder, error::Errors> {
+        let path = path::PathBuf::from(self.path.format(engine)?);
+        let symlink = self.symlink
+            .as_ref()
+            .map(|a| a.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?
+            .unwrap_or_default();
+        let rename = self.rename
+            .as_ref()
+            .map(|t| t.format(engine))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let mut vars = BTreeMap::new();
+        for (name, value) in &self.vars {
+            vars.insert(name.clone(), value.format(engine)?);
+        }
+        let value = spec::TemplateFileBuilder::new(path)
+            .rename(rename)
+            .push_symlinks(symlink.into_iter())
+            .vars(vars)
+            .brackets(self.brackets);
+        Ok(value)
+    }
+}
+
+impl RenderSpecification for SubstituteFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<Box<spec::SpecificationBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<spec::SpecificationBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
 /// Specifies a symbolic link file to be staged into the target directory.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -277,6 +427,9 @@ pub struct Symlink {
     /// Default is the filename of the `target`.
     #[serde(default)]
     pub rename: Option<Template>,
+    /// When true, the target is allowed to point outside the stage root.  Default is `false`.
+    #[serde(default)]
+    pub allow_escape: bool,
     #[serde(skip)]
     non_exhaustive: (),
 }
@@ -284,6 +437,13 @@ pub struct Symlink {
 impl Symlink {
     fn format(&self, engine: &TemplateEngine) -> Result<spec::SymlinkBuilder, error::Errors> {
         let target = path::PathBuf::from(self.target.format(engine)?);
+        // Containment normalizes the target into the stage (stripping a leading `/` and resolving
+        // `..`); use that result so an absolute target such as `/etc/passwd` cannot escape either.
+        let target = if self.allow_escape {
+            target
+        } else {
+            contain::contained(&target)?
+        };
         let value = spec::SymlinkBuilder::new(target).rename(self.rename
             .as_ref()
             .map(|t| t.format(engine))
@@ -308,20 +468,7 @@ fn abs_to_rel(abs: &str) -> Result<path::PathBuf, error::StagingError> {
             .set_context(format!("Path is not absolute (within the stage): {}", abs)));
     }
 
-    let rel = abs.trim_left_matches('/');
-    let mut path = path::PathBuf::new();
-    for part in rel.split('/').filter(|s| !s.is_empty() && *s != ".") {
-        if part == ".." {
-            if !path.pop() {
-                return Err(error::ErrorKind::InvalidConfiguration
-                    .error()
-                    .set_context(format!("Path is outside of staging root: {:?}", abs)));
-            }
-        } else {
-            path.push(part);
-        }
-    }
-    Ok(path)
+    contain::contained(path::Path::new(abs))
 }
 
 #[cfg(test)]
@@ -2,7 +2,9 @@
 
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::iter;
+use std::path;
 use std::vec;
 
 type ErrorCause = Error + Send + Sync + 'static;
@@ -126,11 +128,70 @@ impl fmt::Display for ErrorKind {
     }
 }
 
+/// The filesystem operation that produced a staging failure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StagingOp {
+    /// Creating a directory within the stage.
+    CreateDir,
+    /// Copying from a source file.
+    CopyFrom,
+    /// Creating a destination file within the stage.
+    CreateFile,
+    /// Streaming bytes into a destination file.
+    WriteFrom,
+    /// Creating a symlink to a file.
+    SymlinkFile,
+    /// Creating a symlink to a directory.
+    SymlinkDir,
+}
+
+impl StagingOp {
+    fn describe(self, path: &path::Path) -> String {
+        match self {
+            StagingOp::CreateDir => format!("failed to create directory `{}`", path.display()),
+            StagingOp::CopyFrom => format!("failed to copy from `{}`", path.display()),
+            StagingOp::CreateFile => format!("failed to create file `{}`", path.display()),
+            StagingOp::WriteFrom => format!("failed to write to `{}`", path.display()),
+            StagingOp::SymlinkFile => format!("failed to symlink file `{}`", path.display()),
+            StagingOp::SymlinkDir => format!("failed to symlink directory `{}`", path.display()),
+        }
+    }
+}
+
+/// An IO failure annotated with the operation and path that triggered it.
+///
+/// Modeled on `fs-err`, so a bare `io::Error` is never surfaced without the context a user needs
+/// to act on it.
+#[derive(Debug)]
+struct IoError {
+    inner: io::Error,
+    op: StagingOp,
+    path: path::PathBuf,
+}
+
+impl Error for IoError {
+    fn description(&self) -> &str {
+        "staging IO operation failed"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.inner)
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op.describe(&self.path))
+    }
+}
+
 /// Single staging failure.
 #[derive(Debug)]
 pub struct StagingError {
     kind: ErrorKind,
     context: Option<String>,
+    op: Option<StagingOp>,
+    path: Option<path::PathBuf>,
     cause: Option<Box<ErrorCause>>,
 }
 
@@ -139,6 +200,8 @@ impl StagingError {
         Self {
             kind,
             context: None,
+            op: None,
+            path: None,
             cause: None,
         }
     }
@@ -161,10 +224,36 @@ impl StagingError {
         self
     }
 
+    /// Attach the failing filesystem `op` and `path` to an underlying `io::Error`.
+    pub(crate) fn set_io<P>(mut self, op: StagingOp, path: P, cause: io::Error) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        let path = path.into();
+        self.op = Some(op);
+        self.path = Some(path.clone());
+        self.cause = Some(Box::new(IoError {
+            inner: cause,
+            op,
+            path,
+        }));
+        self
+    }
+
     /// Programmtically process failure.
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    /// The filesystem operation that failed, when the failure originated from an IO operation.
+    pub fn op(&self) -> Option<StagingOp> {
+        self.op
+    }
+
+    /// The path the failed operation was acting on, when known.
+    pub fn path(&self) -> Option<&path::Path> {
+        self.path.as_ref().map(|p| p.as_path())
+    }
 }
 
 impl Error for StagingError {
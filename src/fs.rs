@@ -1,31 +1,170 @@
 //! Stage files on a target.
 
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
 use std::path;
 
+use filetime;
+use tempfile::{NamedTempFile, TempDir};
+
 use super::error;
+use super::StageStats;
 use super::Staging;
 
+/// Callback invoked after each entry is staged, with the staged path and bytes written.
+pub type AfterEntry = FnMut(&path::Path, u64);
+
 /// A location on the filesystem to stage to.
-#[derive(Debug, Clone)]
 pub struct Filesystem {
     root: path::PathBuf,
+    stats: StageStats,
+    atomic: bool,
+    after_entry: Option<Box<AfterEntry>>,
 }
 
 impl Filesystem {
     /// A location to stage files within.
     pub fn new<P: Into<path::PathBuf>>(root: P) -> Self {
-        Self { root: root.into() }
+        Self {
+            root: root.into(),
+            stats: StageStats::default(),
+            atomic: false,
+            after_entry: None,
+        }
+    }
+
+    /// When true, files are written to a temporary file in the destination directory and then
+    /// atomically renamed into place, so the stage is never observed in a half-written state.
+    /// Default is `false`.
+    pub fn atomic(mut self, yes: bool) -> Self {
+        self.atomic = yes;
+        self
+    }
+
+    /// Register a callback invoked after each entry is staged, receiving the staged path and the
+    /// number of bytes written (`0` for directories and symlinks).
+    pub fn on_entry<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&path::Path, u64) + 'static,
+    {
+        self.after_entry = Some(Box::new(callback));
+        self
+    }
+
+    /// Statistics accumulated over every staged entry so far.
+    pub fn stats(&self) -> StageStats {
+        self.stats
+    }
+
+    fn report(&mut self, path: &path::Path, bytes: u64) {
+        if let Some(ref mut callback) = self.after_entry {
+            callback(path, bytes);
+        }
+    }
+
+    /// Stream `src` into a temporary file in `target`'s directory, sync it, then atomically rename
+    /// it over `target`.  The temporary file is removed if any step fails before the rename.
+    ///
+    /// `source` is the path `src` was opened from, when there is one; its permission bits are
+    /// replicated onto the temporary file so an atomic copy lands with the same mode a plain
+    /// `fs::copy` would, rather than the private `0600` a temporary file is created with.
+    fn write_atomic(
+        &self,
+        target: &path::Path,
+        src: &mut Read,
+        source: Option<&path::Path>,
+    ) -> Result<u64, error::StagingError> {
+        let dir = target.parent().unwrap_or_else(|| self.root.as_path());
+        let mut tmp = NamedTempFile::new_in(dir).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateFile, target, e)
+        })?;
+        let bytes = io::copy(src, tmp.as_file_mut()).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::WriteFrom, target, e)
+        })?;
+        tmp.as_file().sync_all().map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::WriteFrom, target, e)
+        })?;
+        copy_mode(source, &tmp, target)?;
+        tmp.persist(target).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::WriteFrom, target, e.error)
+        })?;
+
+        Ok(bytes)
+    }
+}
+
+/// Replicate `source`'s permission bits onto the temporary file `tmp` before it is published.
+///
+/// A [`NamedTempFile`] is created `0600`, so without this an atomic copy would strip a source
+/// file's group/other and executable bits.  When there is no source path (a streamed reader) the
+/// temporary file is given mode `0o644`, regardless of the process umask.
+#[cfg(not(target_os = "windows"))]
+fn copy_mode(
+    source: Option<&path::Path>,
+    tmp: &NamedTempFile,
+    target: &path::Path,
+) -> Result<(), error::StagingError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = match source {
+        Some(source) => {
+            let meta = fs::metadata(source).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CopyFrom, source, e)
+            })?;
+            meta.permissions().mode()
+        }
+        None => 0o644,
+    };
+    tmp.as_file()
+        .set_permissions(fs::Permissions::from_mode(mode))
+        .map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateFile, target, e)
+        })
+}
+
+#[cfg(target_os = "windows")]
+fn copy_mode(
+    _source: Option<&path::Path>,
+    _tmp: &NamedTempFile,
+    _target: &path::Path,
+) -> Result<(), error::StagingError> {
+    // Unix permission bits have no meaning on Windows.
+    Ok(())
+}
+
+impl fmt::Debug for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Filesystem")
+            .field("root", &self.root)
+            .field("stats", &self.stats)
+            .finish()
     }
 }
 
 impl Staging for Filesystem {
     fn directory(&mut self, path: &path::Path) -> Result<(), error::StagingError> {
         let target = self.root.join(path);
-        fs::create_dir_all(&target)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        fs::create_dir_all(&target).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateDir, &target, e)
+        })?;
+        self.stats.directories_created += 1;
+        self.report(&target, 0);
 
         Ok(())
     }
@@ -36,7 +175,23 @@ impl Staging for Filesystem {
         src: &path::Path,
     ) -> Result<(), error::StagingError> {
         let target = self.root.join(dest);
-        fs::copy(&src, &target).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let bytes = if self.atomic {
+            let mut f = fs::File::open(src).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CopyFrom, src, e)
+            })?;
+            self.write_atomic(&target, &mut f, Some(src))?
+        } else {
+            fs::copy(&src, &target).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CopyFrom, src, e)
+            })?
+        };
+        self.stats.files_copied += 1;
+        self.stats.bytes_written += bytes;
+        self.report(&target, bytes);
 
         Ok(())
     }
@@ -47,9 +202,23 @@ impl Staging for Filesystem {
         src: &mut Read,
     ) -> Result<(), error::StagingError> {
         let target = self.root.join(dest);
-        let mut f = fs::File::create(target)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
-        io::copy(src, &mut f).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let bytes = if self.atomic {
+            self.write_atomic(&target, src, None)?
+        } else {
+            let mut f = fs::File::create(&target).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CreateFile, &target, e)
+            })?;
+            io::copy(src, &mut f).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::WriteFrom, &target, e)
+            })?
+        };
+        self.stats.files_copied += 1;
+        self.stats.bytes_written += bytes;
+        self.report(&target, bytes);
 
         Ok(())
     }
@@ -62,8 +231,13 @@ impl Staging for Filesystem {
     ) -> Result<(), error::StagingError> {
         use std::os::windows::fs;
         let path = self.root.join(path);
-        fs::symlink_dir(target, &path)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        fs::symlink_dir(target, &path).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::SymlinkDir, &path, e)
+        })?;
+        self.stats.symlinks_created += 1;
+        self.report(&path, 0);
 
         Ok(())
     }
@@ -76,8 +250,13 @@ impl Staging for Filesystem {
     ) -> Result<(), error::StagingError> {
         use std::os::windows::fs;
         let path = self.root.join(path);
-        fs::symlink_file(target, &path)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        fs::symlink_file(target, &path).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::SymlinkFile, &path, e)
+        })?;
+        self.stats.symlinks_created += 1;
+        self.report(&path, 0);
 
         Ok(())
     }
@@ -90,8 +269,13 @@ impl Staging for Filesystem {
     ) -> Result<(), error::StagingError> {
         use std::os::unix::fs;
         let path = self.root.join(path);
-        fs::symlink(target, &path)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        fs::symlink(target, &path).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::SymlinkDir, &path, e)
+        })?;
+        self.stats.symlinks_created += 1;
+        self.report(&path, 0);
 
         Ok(())
     }
@@ -103,9 +287,522 @@ impl Staging for Filesystem {
         target: &path::Path,
     ) -> Result<(), error::StagingError> {
         use std::os::unix::fs;
-        fs::symlink(target, path)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let path = self.root.join(path);
+        fs::symlink(target, &path).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::SymlinkFile, &path, e)
+        })?;
+        self.stats.symlinks_created += 1;
+        self.report(&path, 0);
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn set_permissions(
+        &mut self,
+        dest: &path::Path,
+        mode: u32,
+    ) -> Result<(), error::StagingError> {
+        use std::os::unix::fs::PermissionsExt;
+        let target = self.root.join(dest);
+        let permissions = fs::Permissions::from_mode(mode);
+        fs::set_permissions(&target, permissions).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("failed to set mode {:o} on {}", mode, target.display()))
+                .set_io(error::StagingOp::CreateFile, &target, e)
+        })?;
+
+        Ok(())
+    }
+
+    fn copy_metadata(
+        &mut self,
+        dest: &path::Path,
+        src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        let target = self.root.join(dest);
+        let meta = fs::metadata(src).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CopyFrom, src, e)
+        })?;
+        fs::set_permissions(&target, meta.permissions()).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateFile, &target, e)
+        })?;
+        let mtime = filetime::FileTime::from_last_modification_time(&meta);
+        filetime::set_file_times(&target, mtime, mtime).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::WriteFrom, &target, e)
+        })?;
+
+        Ok(())
+    }
+}
+
+/// An all-or-nothing driver that stages into a temporary directory and only publishes it once
+/// every action has succeeded.
+///
+/// A [`Filesystem`] mutates its target in place, so a failure partway through leaves a
+/// half-populated tree behind.  `StagingTransaction` instead stages into a
+/// [`tempfile::TempDir`] and, on [`commit`](StagingTransaction::commit), atomically renames the
+/// result over the destination.  The temporary root is canonicalized up front so that path
+/// substitutions behave on platforms (e.g. macOS) where the system temp directory is itself a
+/// symlink.  If the transaction is dropped without committing — which is what happens on any
+/// staging error — the temp dir is removed and the original destination is left untouched.
+pub struct StagingTransaction {
+    dest: path::PathBuf,
+    temp: TempDir,
+    inner: Filesystem,
+}
+
+impl StagingTransaction {
+    /// Begin a transaction that will ultimately publish to `dest`.
+    pub fn new<P: Into<path::PathBuf>>(dest: P) -> Result<Self, error::StagingError> {
+        let dest = dest.into();
+        // Stage into a sibling of the destination so the final publish rename stays on one
+        // filesystem; a cross-device `fs::rename` would otherwise fail with `EXDEV`.
+        let parent = match dest.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => path::PathBuf::from("."),
+        };
+        fs::create_dir_all(&parent).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateDir, &parent, e)
+        })?;
+        let temp = TempDir::new_in(&parent).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateDir, &dest, e)
+        })?;
+        let root = temp.path().canonicalize().map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateDir, temp.path(), e)
+        })?;
+        // Each file is written to a temporary sibling and atomically renamed so the scratch tree
+        // is never observed half-written either.
+        let inner = Filesystem::new(root).atomic(true);
+        Ok(Self { dest, temp, inner })
+    }
+
+    /// Statistics accumulated over every staged entry so far.
+    pub fn stats(&self) -> StageStats {
+        self.inner.stats()
+    }
+
+    /// Publish the staged tree by renaming it over the destination.
+    ///
+    /// The destination's parent is created if needed and any existing destination is removed
+    /// first.  On error the temporary tree is dropped, leaving the destination untouched.
+    pub fn commit(self) -> Result<(), error::StagingError> {
+        let StagingTransaction { dest, temp, .. } = self;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CreateDir, parent, e)
+            })?;
+        }
+        if dest.exists() {
+            fs::remove_dir_all(&dest).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_io(error::StagingOp::CreateDir, &dest, e)
+            })?;
+        }
+        // Release the temp dir so dropping it does not delete what we just published.
+        let staged = temp.into_path();
+        fs::rename(&staged, &dest).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CreateDir, &dest, e)
+        })?;
 
         Ok(())
     }
 }
+
+impl fmt::Debug for StagingTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StagingTransaction")
+            .field("dest", &self.dest)
+            .field("temp", &self.temp.path())
+            .finish()
+    }
+}
+
+impl Staging for StagingTransaction {
+    fn directory(&mut self, path: &path::Path) -> Result<(), error::StagingError> {
+        self.inner.directory(path)
+    }
+
+    fn file_from_path(
+        &mut self,
+        dest: &path::Path,
+        src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.inner.file_from_path(dest, src)
+    }
+
+    fn file_from_reader(
+        &mut self,
+        dest: &path::Path,
+        src: &mut Read,
+    ) -> Result<(), error::StagingError> {
+        self.inner.file_from_reader(dest, src)
+    }
+
+    fn symlink_dir(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.inner.symlink_dir(path, target)
+    }
+
+    fn symlink_file(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.inner.symlink_file(path, target)
+    }
+
+    fn set_permissions(
+        &mut self,
+        dest: &path::Path,
+        mode: u32,
+    ) -> Result<(), error::StagingError> {
+        self.inner.set_permissions(dest, mode)
+    }
+
+    fn copy_metadata(
+        &mut self,
+        dest: &path::Path,
+        src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.inner.copy_metadata(dest, src)
+    }
+}
+
+/// Stage into a scratch area and publish it atomically, rolling back on any error.
+///
+/// `stage` is run against a fresh [`StagingTransaction`] for `dest`: each file is written to a
+/// temporary sibling and atomically renamed, and the scratch tree is only moved into place once
+/// `stage` returns `Ok`.  Any [`error::Errors`] leaves the scratch tree to be dropped, so `dest`
+/// is exactly as it was before the call.
+pub fn transaction<P, F>(dest: P, stage: F) -> Result<(), error::Errors>
+where
+    P: Into<path::PathBuf>,
+    F: FnOnce(&mut StagingTransaction) -> Result<(), error::Errors>,
+{
+    let mut transaction = StagingTransaction::new(dest)?;
+    stage(&mut transaction)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// A dry-run stage that records the planned operations instead of touching disk.
+///
+/// Every directory, file and symlink `Staging` call is appended to an ordered
+/// [`log`](DryRun::log) so a caller can print the plan before committing to a real
+/// [`Filesystem`].  `set_permissions` and `copy_metadata` calls are not recorded.
+#[derive(Debug, Clone, Default)]
+pub struct DryRun {
+    log: Vec<Plan>,
+}
+
+/// A single operation recorded by [`DryRun`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// A directory that would be created.
+    Directory(path::PathBuf),
+    /// A file that would be written, with the source it would be copied from (if any).
+    File {
+        /// The staged destination.
+        dest: path::PathBuf,
+        /// The source being copied, or `None` when streamed from a reader.
+        source: Option<path::PathBuf>,
+        /// The number of bytes counted from a streamed reader, or `None` when copied from a path.
+        len: Option<u64>,
+    },
+    /// A symlink to a file that would be created.
+    SymlinkFile {
+        /// The link's location.
+        path: path::PathBuf,
+        /// The path the link points to.
+        target: path::PathBuf,
+    },
+    /// A symlink to a directory that would be created.
+    SymlinkDir {
+        /// The link's location.
+        path: path::PathBuf,
+        /// The path the link points to.
+        target: path::PathBuf,
+    },
+}
+
+impl DryRun {
+    /// An empty dry-run stage.
+    pub fn new() -> Self {
+        Self { log: Vec::new() }
+    }
+
+    /// The ordered list of operations that would have been performed.
+    pub fn log(&self) -> &[Plan] {
+        &self.log
+    }
+
+    /// Compare this recorded manifest against the current contents of `target`.
+    ///
+    /// In the spirit of a version-control status, each planned entry is reported as
+    /// [`Added`](Diff::Added) (missing on disk), [`Modified`](Diff::Modified) (present but a
+    /// different length), or [`Unchanged`](Diff::Unchanged), and any file found under `target` that
+    /// the manifest does not mention is reported as [`Removed`](Diff::Removed).
+    pub fn diff(&self, target: &path::Path) -> Vec<Diff> {
+        let mut planned = BTreeSet::new();
+        let mut result = Vec::new();
+        for plan in &self.log {
+            match *plan {
+                Plan::Directory(ref dir) => {
+                    planned.insert(dir.clone());
+                    result.push(if dir.is_dir() {
+                        Diff::Unchanged(dir.clone())
+                    } else {
+                        Diff::Added(dir.clone())
+                    });
+                }
+                Plan::File {
+                    ref dest,
+                    ref source,
+                    len,
+                } => {
+                    planned.insert(dest.clone());
+                    result.push(classify_file(dest, source.as_ref(), len));
+                }
+                Plan::SymlinkFile { ref path, .. } | Plan::SymlinkDir { ref path, .. } => {
+                    planned.insert(path.clone());
+                    result.push(if path.symlink_metadata().is_ok() {
+                        Diff::Unchanged(path.clone())
+                    } else {
+                        Diff::Added(path.clone())
+                    });
+                }
+            }
+        }
+
+        for existing in walk_files(target) {
+            if !planned.contains(&existing) {
+                result.push(Diff::Removed(existing));
+            }
+        }
+
+        result
+    }
+}
+
+/// The status of a single path when a [`DryRun`] manifest is compared against a target directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diff {
+    /// Planned but not present on disk.
+    Added(path::PathBuf),
+    /// Present on disk but not mentioned in the manifest.
+    Removed(path::PathBuf),
+    /// Present on disk but a different length than planned.
+    Modified(path::PathBuf),
+    /// Present on disk and matching the manifest.
+    Unchanged(path::PathBuf),
+}
+
+/// Classify a planned file against its destination on disk by comparing lengths.
+fn classify_file(dest: &path::Path, source: Option<&path::PathBuf>, len: Option<u64>) -> Diff {
+    let actual = match fs::metadata(dest) {
+        Ok(meta) => meta.len(),
+        Err(_) => return Diff::Added(dest.to_owned()),
+    };
+    let expected = len.or_else(|| source.and_then(|s| fs::metadata(s).ok().map(|m| m.len())));
+    match expected {
+        Some(expected) if expected != actual => Diff::Modified(dest.to_owned()),
+        // An unknown expected length can't prove a change, so treat it as unchanged.
+        _ => Diff::Unchanged(dest.to_owned()),
+    }
+}
+
+/// Recursively collect the regular files beneath `root`, ignoring anything that cannot be read.
+fn walk_files(root: &path::Path) -> Vec<path::PathBuf> {
+    let mut files = Vec::new();
+    let mut stack = vec![root.to_owned()];
+    while let Some(dir) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                stack.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+impl Staging for DryRun {
+    fn directory(&mut self, path: &path::Path) -> Result<(), error::StagingError> {
+        self.log.push(Plan::Directory(path.to_owned()));
+        Ok(())
+    }
+
+    fn file_from_path(
+        &mut self,
+        dest: &path::Path,
+        src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.log.push(Plan::File {
+            dest: dest.to_owned(),
+            source: Some(src.to_owned()),
+            len: None,
+        });
+        Ok(())
+    }
+
+    fn file_from_reader(
+        &mut self,
+        dest: &path::Path,
+        src: &mut Read,
+    ) -> Result<(), error::StagingError> {
+        // Drain the reader into a sink so the manifest can carry a content length without ever
+        // buffering the whole stream.
+        let len = io::copy(src, &mut io::sink()).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::WriteFrom, dest, e)
+        })?;
+        self.log.push(Plan::File {
+            dest: dest.to_owned(),
+            source: None,
+            len: Some(len),
+        });
+        Ok(())
+    }
+
+    fn symlink_dir(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.log.push(Plan::SymlinkDir {
+            path: path.to_owned(),
+            target: target.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn symlink_file(
+        &mut self,
+        path: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        self.log.push(Plan::SymlinkFile {
+            path: path.to_owned(),
+            target: target.to_owned(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn atomic_copy_preserves_source_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("bin.sh");
+        fs::write(&source, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let root = dir.path().join("stage");
+        fs::create_dir_all(&root).unwrap();
+        let mut staging = Filesystem::new(&root).atomic(true);
+        staging
+            .file_from_path(path::Path::new("bin.sh"), &source)
+            .unwrap();
+
+        let mode = fs::metadata(root.join("bin.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn transaction_publishes_on_success() {
+        let dir = TempDir::new().unwrap();
+        let dest = dir.path().join("published");
+        transaction(&dest, |stage| {
+            stage.file_from_reader(path::Path::new("a.txt"), &mut &b"hi"[..])?;
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_error() {
+        let dir = TempDir::new().unwrap();
+        let dest = dir.path().join("published");
+        let result = transaction(&dest, |stage| {
+            stage.file_from_reader(path::Path::new("a.txt"), &mut &b"hi"[..])?;
+            Err(error::ErrorKind::StagingFailed.error().into())
+        });
+
+        assert!(result.is_err());
+        assert!(!dest.exists(), "destination must be untouched after rollback");
+    }
+
+    #[test]
+    fn diff_reports_added_modified_unchanged_and_removed() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let target = dir.path().join("target");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&target).unwrap();
+
+        fs::write(src.join("same"), b"1234").unwrap();
+        fs::write(src.join("changed"), b"123456").unwrap();
+        fs::write(src.join("new"), b"99").unwrap();
+
+        fs::write(target.join("same"), b"1234").unwrap();
+        fs::write(target.join("changed"), b"12").unwrap();
+        fs::write(target.join("extra"), b"orphan").unwrap();
+
+        let mut plan = DryRun::new();
+        plan.file_from_path(&target.join("same"), &src.join("same"))
+            .unwrap();
+        plan.file_from_path(&target.join("changed"), &src.join("changed"))
+            .unwrap();
+        plan.file_from_path(&target.join("new"), &src.join("new"))
+            .unwrap();
+
+        let diff = plan.diff(&target);
+        assert!(diff.contains(&Diff::Unchanged(target.join("same"))));
+        assert!(diff.contains(&Diff::Modified(target.join("changed"))));
+        assert!(diff.contains(&Diff::Added(target.join("new"))));
+        assert!(diff.contains(&Diff::Removed(target.join("extra"))));
+    }
+}
@@ -0,0 +1,152 @@
+//! Order staged actions so their dependencies resolve before they run.
+//!
+//! The staging pipeline emits [`FsAction`](action::FsAction)s in whatever order the sources appear,
+//! which is fragile when a symlink points at a file produced by another source or when two sources
+//! write overlapping trees.  [`order`] builds a dependency graph keyed by staged path — directories
+//! precede the entries inside them and symlinks follow their in-stage targets — then topologically
+//! sorts it, reporting a precise error on a cycle or on conflicting writes to the same destination.
+
+use std::collections::HashMap;
+use std::path;
+
+use petgraph::algo;
+use petgraph::graph::{DiGraph, NodeIndex};
+
+use action::FsAction;
+use error;
+
+/// Topologically sort `actions` so each directory is created before its contents and each symlink
+/// is created after the in-stage target it points at.
+///
+/// Returns a [`HarvestingFailed`](error::ErrorKind::HarvestingFailed) error on a dependency cycle
+/// or when two actions that are neither directories nor modifiers write to the same destination.
+pub fn order(actions: Vec<Box<FsAction>>) -> Result<Vec<Box<FsAction>>, error::Errors> {
+    let mut graph = DiGraph::<usize, ()>::new();
+    let nodes: Vec<NodeIndex> = (0..actions.len()).map(|i| graph.add_node(i)).collect();
+
+    // The node that produces each staged destination.  Directories are containers and may be
+    // produced by several actions, so only files and symlinks are treated as exclusive writes.
+    let mut files: HashMap<&path::Path, NodeIndex> = HashMap::new();
+    let mut dirs: Vec<(&path::Path, NodeIndex)> = Vec::new();
+    let mut modifiers: Vec<(&path::Path, NodeIndex)> = Vec::new();
+    for (i, action) in actions.iter().enumerate() {
+        let staged = action.staged();
+        if action.is_dir() {
+            dirs.push((staged, nodes[i]));
+        } else if action.is_modifier() {
+            // A modifier shares its destination with the file it acts on, so it is ordered after
+            // that producer rather than treated as a second writer.
+            modifiers.push((staged, nodes[i]));
+        } else if files.insert(staged, nodes[i]).is_some() {
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "conflicting writes to the same destination: {}",
+                    staged.display()
+                ))
+                .into());
+        }
+    }
+
+    // A directory must exist before anything staged inside it.
+    for (i, action) in actions.iter().enumerate() {
+        let staged = action.staged();
+        for &(dir, dir_node) in &dirs {
+            if staged != dir && staged.starts_with(dir) {
+                graph.add_edge(dir_node, nodes[i], ());
+            }
+        }
+    }
+
+    // A symlink must be created after the in-stage target it points at.
+    for (i, action) in actions.iter().enumerate() {
+        if let Some(target) = action.link_target() {
+            if let Some(&producer) = files.get(target) {
+                graph.add_edge(producer, nodes[i], ());
+            }
+        }
+    }
+
+    // A modifier must run after the action that produced the file it acts on.
+    for &(staged, node) in &modifiers {
+        if let Some(&producer) = files.get(staged) {
+            graph.add_edge(producer, node, ());
+        }
+    }
+
+    let sorted = algo::toposort(&graph, None).map_err(|cycle| {
+        let staged = actions[graph[cycle.node_id()]].staged();
+        error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!(
+                "staging actions form a dependency cycle at: {}",
+                staged.display()
+            ))
+    })?;
+
+    let mut actions: Vec<Option<Box<FsAction>>> = actions.into_iter().map(Some).collect();
+    let ordered = sorted
+        .into_iter()
+        .map(|node| actions[graph[node]].take().expect("each node visited once"))
+        .collect();
+
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use action::{CopyFile, CreateDirectory, SetPermissions, Symlink};
+
+    fn staged(actions: &[Box<FsAction>]) -> Vec<path::PathBuf> {
+        actions.iter().map(|a| a.staged().to_owned()).collect()
+    }
+
+    #[test]
+    fn orders_directory_before_its_contents() {
+        let actions: Vec<Box<FsAction>> = vec![
+            Box::new(CopyFile::new("stage/dir/file", "src/file")),
+            Box::new(CreateDirectory::new("stage/dir")),
+        ];
+        let ordered = staged(&order(actions).unwrap());
+        let dir = ordered.iter().position(|p| p == path::Path::new("stage/dir"));
+        let file = ordered
+            .iter()
+            .position(|p| p == path::Path::new("stage/dir/file"));
+        assert!(dir < file, "directory must precede its contents: {:?}", ordered);
+    }
+
+    #[test]
+    fn orders_symlink_after_its_target() {
+        let actions: Vec<Box<FsAction>> = vec![
+            Box::new(Symlink::new("stage/link", "stage/file")),
+            Box::new(CopyFile::new("stage/file", "src/file")),
+        ];
+        let ordered = staged(&order(actions).unwrap());
+        let target = ordered.iter().position(|p| p == path::Path::new("stage/file"));
+        let link = ordered.iter().position(|p| p == path::Path::new("stage/link"));
+        assert!(target < link, "symlink must follow its target: {:?}", ordered);
+    }
+
+    #[test]
+    fn orders_modifier_after_its_file() {
+        let actions: Vec<Box<FsAction>> = vec![
+            Box::new(SetPermissions::new("stage/file", 0o755)),
+            Box::new(CopyFile::new("stage/file", "src/file")),
+        ];
+        let ordered = order(actions).unwrap();
+        // The copy produces the file; the permission change must follow it, not collide with it.
+        assert!(!ordered[0].is_modifier());
+        assert!(ordered[1].is_modifier());
+    }
+
+    #[test]
+    fn rejects_conflicting_writes() {
+        let actions: Vec<Box<FsAction>> = vec![
+            Box::new(CopyFile::new("stage/file", "a/file")),
+            Box::new(CopyFile::new("stage/file", "b/file")),
+        ];
+        assert!(order(actions).is_err());
+    }
+}
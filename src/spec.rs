@@ -1,13 +1,20 @@
 //! High-level specification for staging files.
 
+use std::collections::BTreeMap;
 use std::ffi;
 use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader};
 use std::path;
+use std::rc::Rc;
 
-use globwalk;
+use ignore::overrides::OverrideBuilder;
+use ignore::types::TypesBuilder;
+use ignore::WalkBuilder;
 
 use super::Staging;
 use error;
+use template::TemplateEngine;
 
 /// Create concrete filesystem specs.
 pub trait SpecificationBuilder: fmt::Debug {
@@ -41,6 +48,9 @@ pub struct SourceFileBuilder {
     path: path::PathBuf,
     rename: Option<String>,
     symlink: Vec<String>,
+    preserve_metadata: bool,
+    preserve_mode: bool,
+    mode: Option<String>,
 }
 
 impl SourceFileBuilder {
@@ -55,6 +65,9 @@ impl SourceFileBuilder {
             path: source.into(),
             rename: None,
             symlink: Default::default(),
+            preserve_metadata: false,
+            preserve_mode: false,
+            mode: None,
         }
     }
 
@@ -71,64 +84,417 @@ impl SourceFileBuilder {
         self
     }
 
+    /// When true, the source file's modification time and permission bits are replicated onto the
+    /// staged file.  Default is `false`.
+    pub fn preserve_metadata(mut self, yes: bool) -> Self {
+        self.preserve_metadata = yes;
+        self
+    }
+
+    /// Specifies the permission mode, as an octal string (e.g. `"0755"`), to apply to the staged
+    /// file.  Default is to leave the copied file's mode untouched.
+    pub fn mode<S: Into<String>>(mut self, mode: Option<S>) -> Self {
+        self.mode = mode.map(|m| m.into());
+        self
+    }
+
+    /// When true, the source file's Unix permission bits are replicated onto the staged file.
+    /// An explicit [`mode`](Self::mode) takes precedence, and this is redundant with
+    /// [`preserve_metadata`](Self::preserve_metadata), which already replicates the mode.  Default
+    /// is `false`.
+    pub fn preserve_mode(mut self, yes: bool) -> Self {
+        self.preserve_mode = yes;
+        self
+    }
+
     /// Resolve a specification for a given `target_dir`.
     pub fn resolve(self, target_dir: &path::Path) -> Result<SourceFile, error::Errors> {
         let SourceFileBuilder {
             path: source,
             rename,
             symlink,
+            preserve_metadata,
+            preserve_mode,
+            mode,
         } = self;
 
-        let dest = {
-            let default_name = source.file_name().ok_or_else(|| {
-                error::ErrorKind::HarvestingFailed
-                    .error()
-                    .set_context(format!("SourceFile is missing a filename: {:?}", source))
-            })?;
-            let dest = rename
-                .as_ref()
-                .map(|n| ffi::OsStr::new(n))
-                .unwrap_or(default_name);
-            let dest = path::Path::new(dest);
-            if dest.file_name() != Some(dest.as_os_str()) {
+        let mode = mode.as_ref().map(|m| parse_mode(m)).map_or(Ok(None), |r| r.map(Some))?;
+
+        let dest = resolve_dest(&source, rename.as_ref(), target_dir, "SourceFile")?;
+        let symlinks = resolve_symlinks(&symlink, target_dir, "SourceFile")?;
+
+        let spec = SourceFile {
+            source,
+            dest,
+            symlinks,
+            preserve_metadata,
+            preserve_mode,
+            mode,
+        };
+
+        Ok(spec)
+    }
+}
+
+/// Resolve the staged destination for a single renamed file, rejecting renames that would change
+/// directories.  `label` names the specification for error messages.
+fn resolve_dest(
+    source: &path::Path,
+    rename: Option<&String>,
+    target_dir: &path::Path,
+    label: &str,
+) -> Result<path::PathBuf, error::Errors> {
+    let default_name = source.file_name().ok_or_else(|| {
+        error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!("{} is missing a filename: {:?}", label, source))
+    })?;
+    let dest = rename
+        .map(|n| ffi::OsStr::new(n))
+        .unwrap_or(default_name);
+    let dest = path::Path::new(dest);
+    if dest.file_name() != Some(dest.as_os_str()) {
+        Err(error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!(
+                "{} rename must not change directories: {:?}",
+                label, dest
+            )))?;
+    }
+    Ok(target_dir.join(dest))
+}
+
+/// Resolve the staged locations of sibling symlinks, rejecting names that would change directories.
+fn resolve_symlinks(
+    symlink: &[String],
+    target_dir: &path::Path,
+    label: &str,
+) -> Result<Vec<path::PathBuf>, error::Errors> {
+    symlink
+        .iter()
+        .map(|s| {
+            let symlink = path::Path::new(s);
+            if symlink.file_name() != Some(symlink.as_os_str()) {
                 Err(error::ErrorKind::HarvestingFailed
                     .error()
                     .set_context(format!(
-                        "SourceFile rename must not change directories: {:?}",
-                        dest
+                        "{} symlink must not change directories: {:?}",
+                        label, symlink
                     )))?;
             }
-            target_dir.join(dest)
+            Ok(target_dir.join(symlink))
+        })
+        .collect()
+}
+
+/// The source file's Unix permission bits, or `None` when they cannot be read or on non-Unix
+/// targets where file modes do not apply.
+#[cfg(not(target_os = "windows"))]
+fn source_mode(source: &path::Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(source).ok().map(|m| m.permissions().mode())
+}
+
+/// The source file's Unix permission bits, or `None` when they cannot be read or on non-Unix
+/// targets where file modes do not apply.
+#[cfg(target_os = "windows")]
+fn source_mode(_source: &path::Path) -> Option<u32> {
+    None
+}
+
+/// Parse an octal permission string such as `"0755"` into its mode bits.
+fn parse_mode(mode: &str) -> Result<u32, error::Errors> {
+    let trimmed = mode.trim_start_matches("0o");
+    u32::from_str_radix(trimmed, 8)
+        .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e).into())
+}
+
+impl SpecificationBuilder for SourceFileBuilder {
+    fn resolve(&self, target_dir: &path::Path) -> Result<Box<Specification>, error::Errors> {
+        self.clone().resolve(target_dir).map(|s| {
+            let s: Box<Specification> = Box::new(s);
+            s
+        })
+    }
+}
+
+/// Specifies a file to be staged into the target directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceFile {
+    source: path::PathBuf,
+    dest: path::PathBuf,
+    symlinks: Vec<path::PathBuf>,
+    preserve_metadata: bool,
+    preserve_mode: bool,
+    mode: Option<u32>,
+}
+
+impl Specification for SourceFile {
+    fn stage(&self, stage: &mut Staging) -> Result<(), error::Errors> {
+        stage.file_from_path(&self.dest, &self.source)?;
+        if self.preserve_metadata {
+            stage.copy_metadata(&self.dest, &self.source)?;
+        }
+        // `preserve_metadata` already replicates the source's permission bits, so only derive the
+        // mode from `preserve_mode` when metadata is not being preserved, to avoid setting it twice.
+        let mode = match self.mode {
+            Some(mode) => Some(mode),
+            None if self.preserve_mode && !self.preserve_metadata => source_mode(&self.source),
+            None => None,
         };
+        if let Some(mode) = mode {
+            stage.set_permissions(&self.dest, mode)?;
+        }
 
-        let symlinks: Result<Vec<_>, error::StagingError> = symlink
-            .iter()
-            .map(|s| {
-                let symlink = path::Path::new(s);
-                if symlink.file_name() != Some(symlink.as_os_str()) {
+        for symlink in &self.symlinks {
+            stage.symlink_file(symlink, &self.dest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Specifies a file whose contents are rendered through the `TemplateEngine` while staging.
+#[derive(Clone)]
+pub struct RenderFileBuilder {
+    path: path::PathBuf,
+    rename: Option<String>,
+    symlink: Vec<String>,
+    engine: Rc<TemplateEngine>,
+    allow_binary: bool,
+}
+
+impl fmt::Debug for RenderFileBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RenderFileBuilder")
+            .field("path", &self.path)
+            .field("rename", &self.rename)
+            .field("symlink", &self.symlink)
+            .field("allow_binary", &self.allow_binary)
+            .finish()
+    }
+}
+
+impl RenderFileBuilder {
+    /// Specifies a file whose contents are rendered before being staged.
+    ///
+    /// - `source`: full path of the file to be rendered into the target directory
+    /// - `engine`: the template engine supplying the rendering context
+    pub fn new<P>(source: P, engine: Rc<TemplateEngine>) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            path: source.into(),
+            rename: None,
+            symlink: Default::default(),
+            engine,
+            allow_binary: false,
+        }
+    }
+
+    /// Specifies the name the target file should be renamed as when rendering from the source file.
+    /// Default is the filename of the source file.
+    pub fn rename<S: Into<String>>(mut self, filename: Option<S>) -> Self {
+        self.rename = filename.map(|f| f.into());
+        self
+    }
+
+    /// Specifies symbolic links to `rename` in the same target directory.
+    pub fn push_symlinks<I: Iterator<Item = String>>(mut self, symlinks: I) -> Self {
+        self.symlink.extend(symlinks);
+        self
+    }
+
+    /// When true, a source that is not valid UTF-8 is copied unrendered rather than producing an
+    /// error.  Default is `false`.
+    pub fn allow_binary(mut self, yes: bool) -> Self {
+        self.allow_binary = yes;
+        self
+    }
+
+    /// Resolve a specification for a given `target_dir`.
+    pub fn resolve(self, target_dir: &path::Path) -> Result<RenderFile, error::Errors> {
+        let RenderFileBuilder {
+            path: source,
+            rename,
+            symlink,
+            engine,
+            allow_binary,
+        } = self;
+
+        let dest = resolve_dest(&source, rename.as_ref(), target_dir, "TemplateFile")?;
+        let symlinks = resolve_symlinks(&symlink, target_dir, "TemplateFile")?;
+
+        let spec = RenderFile {
+            source,
+            dest,
+            symlinks,
+            engine,
+            allow_binary,
+        };
+
+        Ok(spec)
+    }
+}
+
+impl SpecificationBuilder for RenderFileBuilder {
+    fn resolve(&self, target_dir: &path::Path) -> Result<Box<Specification>, error::Errors> {
+        self.clone().resolve(target_dir).map(|s| {
+            let s: Box<Specification> = Box::new(s);
+            s
+        })
+    }
+}
+
+/// Specifies a file whose contents are rendered through the `TemplateEngine` while staging.
+#[derive(Clone)]
+pub struct RenderFile {
+    source: path::PathBuf,
+    dest: path::PathBuf,
+    symlinks: Vec<path::PathBuf>,
+    engine: Rc<TemplateEngine>,
+    allow_binary: bool,
+}
+
+impl fmt::Debug for RenderFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RenderFile")
+            .field("source", &self.source)
+            .field("dest", &self.dest)
+            .field("symlinks", &self.symlinks)
+            .field("allow_binary", &self.allow_binary)
+            .finish()
+    }
+}
+
+impl Specification for RenderFile {
+    fn stage(&self, stage: &mut Staging) -> Result<(), error::Errors> {
+        let raw = fs::read(&self.source)
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+        match String::from_utf8(raw) {
+            Ok(text) => {
+                let rendered = self.engine.render(&text)?;
+                let mut bytes = rendered.as_bytes();
+                stage.file_from_reader(&self.dest, &mut bytes)?;
+            }
+            Err(_) => {
+                if !self.allow_binary {
                     Err(error::ErrorKind::HarvestingFailed
                         .error()
                         .set_context(format!(
-                            "SourceFile symlink must not change directories: {:?}",
-                            dest
+                            "TemplateFile source is not valid UTF-8: {:?}",
+                            self.source
                         )))?;
                 }
-                let symlink = target_dir.join(symlink);
-                Ok(symlink)
-            })
-            .collect();
+                stage.file_from_path(&self.dest, &self.source)?;
+            }
+        }
 
-        let spec = SourceFile {
+        for symlink in &self.symlinks {
+            stage.symlink_file(symlink, &self.dest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Specifies a file whose contents have `{name}`/`[name]` placeholders substituted while staging.
+///
+/// Unlike [`RenderFileBuilder`], which runs the full template engine, this performs a simple
+/// per-line variable substitution driven by a name→value map, which is handy for injecting
+/// per-deployment values into an otherwise-static template tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TemplateFileBuilder {
+    path: path::PathBuf,
+    rename: Option<String>,
+    symlink: Vec<String>,
+    vars: BTreeMap<String, String>,
+    brackets: bool,
+}
+
+impl TemplateFileBuilder {
+    /// Specifies a file to be substituted and staged into the target directory.
+    ///
+    /// - `source`: full path of the file to be rendered into the target directory
+    pub fn new<P>(source: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            path: source.into(),
+            rename: None,
+            symlink: Default::default(),
+            vars: Default::default(),
+            brackets: false,
+        }
+    }
+
+    /// Specifies the name the target file should be renamed as when copying from the source file.
+    /// Default is the filename of the source file.
+    pub fn rename<S: Into<String>>(mut self, filename: Option<S>) -> Self {
+        self.rename = filename.map(|f| f.into());
+        self
+    }
+
+    /// Specifies symbolic links to `rename` in the same target directory.
+    pub fn push_symlinks<I: Iterator<Item = String>>(mut self, symlinks: I) -> Self {
+        self.symlink.extend(symlinks);
+        self
+    }
+
+    /// Bind a single placeholder `name` to `value`.
+    pub fn insert<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Bind a map of placeholder names to values.
+    pub fn vars<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    /// When true, `[name]` is also recognized as a placeholder alongside `{name}`.  Default is
+    /// `false`, since `[...]` occurs naturally in content such as TOML section headers and Markdown
+    /// links, where treating it as a placeholder would force an unwanted escape or error.
+    pub fn brackets(mut self, yes: bool) -> Self {
+        self.brackets = yes;
+        self
+    }
+
+    /// Resolve a specification for a given `target_dir`.
+    ///
+    /// The target directory is exposed to the template as the built-in `target_dir` placeholder
+    /// unless the caller has already bound that name.
+    pub fn resolve(self, target_dir: &path::Path) -> Result<TemplateFile, error::Errors> {
+        let TemplateFileBuilder {
+            path: source,
+            rename,
+            symlink,
+            mut vars,
+            brackets,
+        } = self;
+
+        let dest = resolve_dest(&source, rename.as_ref(), target_dir, "TemplateFile")?;
+        let symlinks = resolve_symlinks(&symlink, target_dir, "TemplateFile")?;
+
+        vars.entry("target_dir".to_owned())
+            .or_insert_with(|| target_dir.to_string_lossy().into_owned());
+
+        let spec = TemplateFile {
             source,
             dest,
-            symlinks: symlinks?,
+            symlinks,
+            vars,
+            brackets,
         };
 
         Ok(spec)
     }
 }
 
-impl SpecificationBuilder for SourceFileBuilder {
+impl SpecificationBuilder for TemplateFileBuilder {
     fn resolve(&self, target_dir: &path::Path) -> Result<Box<Specification>, error::Errors> {
         self.clone().resolve(target_dir).map(|s| {
             let s: Box<Specification> = Box::new(s);
@@ -137,17 +503,37 @@ impl SpecificationBuilder for SourceFileBuilder {
     }
 }
 
-/// Specifies a file to be staged into the target directory.
+/// Specifies a file whose `{name}` (and, opt-in, `[name]`) placeholders are substituted while staging.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct SourceFile {
+pub struct TemplateFile {
     source: path::PathBuf,
     dest: path::PathBuf,
     symlinks: Vec<path::PathBuf>,
+    vars: BTreeMap<String, String>,
+    brackets: bool,
 }
 
-impl Specification for SourceFile {
+impl Specification for TemplateFile {
     fn stage(&self, stage: &mut Staging) -> Result<(), error::Errors> {
-        stage.file_from_path(&self.dest, &self.source)?;
+        let file = fs::File::open(&self.source)
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+        // Substitute a line at a time; the rendered output is collected in memory in full before
+        // it is staged.
+        let mut rendered = String::new();
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+            if read == 0 {
+                break;
+            }
+            rendered.push_str(&substitute(&line, &self.vars, self.brackets)?);
+        }
+
+        stage.file_from_reader(&self.dest, &mut rendered.as_bytes())?;
 
         for symlink in &self.symlinks {
             stage.symlink_file(symlink, &self.dest)?;
@@ -157,6 +543,67 @@ impl Specification for SourceFile {
     }
 }
 
+/// Replace `{name}` placeholders in `line` with their bound values, and `[name]` too when
+/// `brackets` is set.
+///
+/// A doubled delimiter (`{{`, `}}`, and — when `brackets` is set — `[[`, `]]`) is an escaped
+/// literal; an unbound placeholder is a
+/// [`HarvestingFailed`](error::ErrorKind::HarvestingFailed) error naming the missing placeholder.
+/// With `brackets` unset, `[` and `]` are ordinary characters so content like TOML section headers
+/// and Markdown links passes through untouched.
+fn substitute(
+    line: &str,
+    vars: &BTreeMap<String, String>,
+    brackets: bool,
+) -> Result<String, error::Errors> {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '[' if c == '{' || brackets => {
+                let close = if c == '{' { '}' } else { ']' };
+                if chars.peek() == Some(&c) {
+                    chars.next();
+                    out.push(c);
+                    continue;
+                }
+                let mut name = String::new();
+                let mut closed = false;
+                while let Some(n) = chars.next() {
+                    if n == close {
+                        closed = true;
+                        break;
+                    }
+                    name.push(n);
+                }
+                if !closed {
+                    // No closing brace on this line; emit the text verbatim.
+                    out.push(c);
+                    out.push_str(&name);
+                    continue;
+                }
+                match vars.get(&name) {
+                    Some(value) => out.push_str(value),
+                    None => Err(error::ErrorKind::HarvestingFailed
+                        .error()
+                        .set_context(format!(
+                            "TemplateFile has no value for placeholder {:?}",
+                            name
+                        )))?,
+                }
+            }
+            '}' | ']' if c == '}' || brackets => {
+                if chars.peek() == Some(&c) {
+                    chars.next();
+                }
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
 /// Specifies a collection of files to be staged into the target directory.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SourceFilesBuilder {
@@ -164,6 +611,20 @@ pub struct SourceFilesBuilder {
     pattern: Vec<String>,
     follow_links: bool,
     allow_empty: bool,
+    preserve_metadata: bool,
+    respect_gitignore: bool,
+    respect_ignore_files: bool,
+    custom_ignore_filenames: Vec<String>,
+    types: Vec<(String, String)>,
+    select_types: Vec<String>,
+    negate_types: Vec<String>,
+    include_hidden: bool,
+    extensions: Vec<String>,
+    mode: Option<String>,
+    min_depth: Option<usize>,
+    max_depth: Option<usize>,
+    sort: bool,
+    contents_first: bool,
 }
 
 impl SourceFilesBuilder {
@@ -180,12 +641,27 @@ impl SourceFilesBuilder {
             pattern: Default::default(),
             follow_links: false,
             allow_empty: false,
+            preserve_metadata: false,
+            respect_gitignore: true,
+            respect_ignore_files: true,
+            custom_ignore_filenames: Default::default(),
+            types: Default::default(),
+            select_types: Default::default(),
+            negate_types: Default::default(),
+            include_hidden: false,
+            extensions: Default::default(),
+            mode: None,
+            min_depth: None,
+            max_depth: None,
+            sort: true,
+            contents_first: false,
         }
     }
 
     /// Specifies the `pattern` for executing the recursive/multifile match.
     ///
-    /// `pattern` uses [gitignore][gitignore] syntax.
+    /// `pattern` uses [gitignore][gitignore] syntax and is applied on top of any `.gitignore`
+    /// files discovered along the walk.
     ///
     /// [gitignore]: https://git-scm.com/docs/gitignore#_pattern_format
     pub fn push_patterns<I: Iterator<Item = String>>(mut self, patterns: I) -> Self {
@@ -193,6 +669,97 @@ impl SourceFilesBuilder {
         self
     }
 
+    /// When true (the default), `.gitignore` files discovered within the source tree, the global
+    /// gitignore and `.git/info/exclude` are honored, with deeper files overriding shallower ones
+    /// as `git` does.  It also controls whether ignore files above the source root are read.
+    /// `.ignore` files are controlled by [`respect_ignore_files`](Self::respect_ignore_files).
+    pub fn respect_gitignore(mut self, yes: bool) -> Self {
+        self.respect_gitignore = yes;
+        self
+    }
+
+    /// When true (the default), `.ignore` files and any filenames registered with
+    /// [`add_custom_ignore_filename`](Self::add_custom_ignore_filename) are honored along the walk.
+    pub fn respect_ignore_files(mut self, yes: bool) -> Self {
+        self.respect_ignore_files = yes;
+        self
+    }
+
+    /// Register an additional ignore filename (beyond `.gitignore`/`.ignore`) to honor along the
+    /// walk, with the same deeper-overrides-shallower precedence.
+    pub fn add_custom_ignore_filename<S: Into<String>>(mut self, name: S) -> Self {
+        self.custom_ignore_filenames.push(name.into());
+        self
+    }
+
+    /// Define a named file type as a glob, e.g. `add_type("rust", "*.rs")`, for use with
+    /// [`select_type`](Self::select_type) and [`negate_type`](Self::negate_type).
+    pub fn add_type<N: Into<String>, G: Into<String>>(mut self, name: N, glob: G) -> Self {
+        self.types.push((name.into(), glob.into()));
+        self
+    }
+
+    /// Restrict the harvest to files matching the named type.
+    pub fn select_type<S: Into<String>>(mut self, name: S) -> Self {
+        self.select_types.push(name.into());
+        self
+    }
+
+    /// Exclude files matching the named type from the harvest.
+    pub fn negate_type<S: Into<String>>(mut self, name: S) -> Self {
+        self.negate_types.push(name.into());
+        self
+    }
+
+    /// When true, hidden files and directories (those whose name begins with `.`) are included.
+    /// Default is `false`.
+    pub fn include_hidden(mut self, yes: bool) -> Self {
+        self.include_hidden = yes;
+        self
+    }
+
+    /// Restrict the harvest to files with one of the given extensions (without the leading dot).
+    ///
+    /// An empty set (the default) matches every extension.
+    pub fn push_extensions<I: Iterator<Item = String>>(mut self, extensions: I) -> Self {
+        self.extensions.extend(extensions);
+        self
+    }
+
+    /// Specifies the permission mode, as an octal string (e.g. `"0755"`), to apply to every staged
+    /// file.  Default is to leave each copied file's mode untouched.
+    pub fn mode<S: Into<String>>(mut self, mode: Option<S>) -> Self {
+        self.mode = mode.map(|m| m.into());
+        self
+    }
+
+    /// Only stage files at least `depth` directories below the source root (the root itself is
+    /// depth `0`).  Default is no lower bound.
+    pub fn min_depth(mut self, depth: Option<usize>) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Do not descend more than `depth` directories below the source root.  Default is unbounded.
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// When true (the default), harvested files are staged in deterministic lexicographic order so
+    /// the resulting tree is reproducible.
+    pub fn sort(mut self, yes: bool) -> Self {
+        self.sort = yes;
+        self
+    }
+
+    /// When true, a directory's contents are staged before shallower entries (deepest-first).
+    /// Default is `false`.
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.contents_first = yes;
+        self
+    }
+
     /// When true, symbolic links are followed as if they were normal directories and files.
     /// If a symbolic link is broken or is involved in a loop, an error is yielded.
     pub fn follow_links(mut self, yes: bool) -> Self {
@@ -210,14 +777,36 @@ impl SourceFilesBuilder {
         self
     }
 
+    /// When true, each source file's modification time and permission bits are replicated onto the
+    /// staged file.  Default is `false`.
+    pub fn preserve_metadata(mut self, yes: bool) -> Self {
+        self.preserve_metadata = yes;
+        self
+    }
+
     /// Resolve a specification for a given `target_dir`.
     pub fn resolve(self, target_dir: &path::Path) -> Result<SourceFiles, error::Errors> {
+        let mode = self.mode.as_ref().map(|m| parse_mode(m)).map_or(Ok(None), |r| r.map(Some))?;
         let spec = SourceFiles {
             target_dir: target_dir.to_owned(),
             path: self.path,
             pattern: self.pattern,
             follow_links: self.follow_links,
             allow_empty: self.allow_empty,
+            preserve_metadata: self.preserve_metadata,
+            respect_gitignore: self.respect_gitignore,
+            respect_ignore_files: self.respect_ignore_files,
+            custom_ignore_filenames: self.custom_ignore_filenames,
+            types: self.types,
+            select_types: self.select_types,
+            negate_types: self.negate_types,
+            include_hidden: self.include_hidden,
+            extensions: self.extensions,
+            mode,
+            min_depth: self.min_depth,
+            max_depth: self.max_depth,
+            sort: self.sort,
+            contents_first: self.contents_first,
         };
         Ok(spec)
     }
@@ -240,28 +829,121 @@ pub struct SourceFiles {
     pattern: Vec<String>,
     follow_links: bool,
     allow_empty: bool,
+    preserve_metadata: bool,
+    respect_gitignore: bool,
+    respect_ignore_files: bool,
+    custom_ignore_filenames: Vec<String>,
+    types: Vec<(String, String)>,
+    select_types: Vec<String>,
+    negate_types: Vec<String>,
+    include_hidden: bool,
+    extensions: Vec<String>,
+    mode: Option<u32>,
+    min_depth: Option<usize>,
+    max_depth: Option<usize>,
+    sort: bool,
+    contents_first: bool,
 }
 
 impl Specification for SourceFiles {
     fn stage(&self, stage: &mut Staging) -> Result<(), error::Errors> {
         let source_root = self.path.as_path();
 
-        let mut empty = true;
-        let walker = globwalk::GlobWalker::from_patterns(source_root, &self.pattern)
-            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?
-            .follow_links(self.follow_links);
-        for entry in walker {
+        let mut overrides = OverrideBuilder::new(source_root);
+        for pattern in &self.pattern {
+            overrides
+                .add(pattern)
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+        }
+        let overrides = overrides
+            .build()
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+
+        let mut builder = WalkBuilder::new(source_root);
+        builder
+            .follow_links(self.follow_links)
+            .hidden(!self.include_hidden)
+            .parents(self.respect_gitignore)
+            .git_ignore(self.respect_gitignore)
+            .git_global(self.respect_gitignore)
+            .git_exclude(self.respect_gitignore)
+            .ignore(self.respect_ignore_files)
+            .overrides(overrides);
+        if self.respect_ignore_files {
+            for name in &self.custom_ignore_filenames {
+                builder.add_custom_ignore_filename(name);
+            }
+        }
+        if !self.types.is_empty() || !self.select_types.is_empty()
+            || !self.negate_types.is_empty()
+        {
+            let mut types = TypesBuilder::new();
+            for &(ref name, ref glob) in &self.types {
+                types
+                    .add(name, glob)
+                    .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+            }
+            for name in &self.select_types {
+                types.select(name);
+            }
+            for name in &self.negate_types {
+                types.negate(name);
+            }
+            let matcher = types
+                .build()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+            builder.types(matcher);
+        }
+        if let Some(max) = self.max_depth {
+            builder.max_depth(Some(max));
+        }
+
+        // Harvest first so that ordering and depth bounds can be applied before any staging.
+        let mut sources: Vec<(usize, path::PathBuf)> = Vec::new();
+        for entry in builder.build() {
             let entry = entry.map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
             let source = entry.path();
             if source.is_dir() {
                 continue;
             }
+            let depth = entry.depth();
+            if self.min_depth.map_or(false, |min| depth < min) {
+                continue;
+            }
+            if !self.extensions.is_empty() {
+                let matches = source
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| self.extensions.iter().any(|wanted| wanted == e))
+                    .unwrap_or(false);
+                if !matches {
+                    continue;
+                }
+            }
+            sources.push((depth, source.to_owned()));
+        }
+
+        if self.sort {
+            sources.sort_by(|&(_, ref a), &(_, ref b)| a.cmp(b));
+        }
+        if self.contents_first {
+            // Stable sort by descending depth so a directory's contents precede shallower entries.
+            sources.sort_by(|&(a, _), &(b, _)| b.cmp(&a));
+        }
+
+        let empty = sources.is_empty();
+        for &(_, ref source) in &sources {
             let dest = source
                 .strip_prefix(source_root)
                 .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
             let dest = self.target_dir.join(dest);
             stage.file_from_path(&dest, source)?;
-            empty = false;
+            if self.preserve_metadata {
+                stage.copy_metadata(&dest, source)?;
+            }
+            if let Some(mode) = self.mode {
+                stage.set_permissions(&dest, mode)?;
+            }
         }
 
         if empty {
@@ -374,3 +1056,70 @@ impl Specification for Symlink {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_mode_reads_leading_zero_octal() {
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+    }
+
+    #[test]
+    fn parse_mode_reads_bare_octal() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+    }
+
+    #[test]
+    fn parse_mode_reads_rust_style_prefix() {
+        assert_eq!(parse_mode("0o640").unwrap(), 0o640);
+    }
+
+    #[test]
+    fn parse_mode_rejects_non_octal() {
+        assert!(parse_mode("9").is_err());
+        assert!(parse_mode("garbage").is_err());
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn substitute_replaces_brace_placeholders() {
+        let vars = vars(&[("name", "world")]);
+        assert_eq!(substitute("hello {name}", &vars, false).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn substitute_errors_on_unknown_placeholder() {
+        let vars = vars(&[]);
+        assert!(substitute("hello {missing}", &vars, false).is_err());
+    }
+
+    #[test]
+    fn substitute_leaves_brackets_untouched_by_default() {
+        let vars = vars(&[("name", "world")]);
+        // A TOML section header must survive verbatim when bracket syntax is off.
+        assert_eq!(
+            substitute("[section]", &vars, false).unwrap(),
+            "[section]"
+        );
+    }
+
+    #[test]
+    fn substitute_honors_brackets_when_enabled() {
+        let vars = vars(&[("name", "world")]);
+        assert_eq!(substitute("[name]", &vars, true).unwrap(), "world");
+    }
+
+    #[test]
+    fn substitute_unescapes_doubled_braces() {
+        let vars = vars(&[]);
+        assert_eq!(substitute("{{literal}}", &vars, false).unwrap(), "{literal}");
+    }
+}
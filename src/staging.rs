@@ -4,6 +4,19 @@ use std::path;
 
 use error;
 
+/// Counts of the work performed over a staging run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StageStats {
+    /// Number of files copied into the stage.
+    pub files_copied: usize,
+    /// Number of symlinks created within the stage.
+    pub symlinks_created: usize,
+    /// Number of directories created within the stage.
+    pub directories_created: usize,
+    /// Total number of bytes written while copying files.
+    pub bytes_written: u64,
+}
+
 /// Build up a staged filesystem.
 pub trait Staging {
     /// Create a directory within the stage.
@@ -15,8 +28,11 @@ pub trait Staging {
         dest: &path::Path,
         src: &path::Path,
     ) -> Result<(), error::StagingError> {
-        let mut f =
-            fs::File::open(src).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let mut f = fs::File::open(src).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_io(error::StagingOp::CopyFrom, src, e)
+        })?;
         self.file_from_reader(dest, &mut f)
     }
 
@@ -40,4 +56,27 @@ pub trait Staging {
         path: &path::Path,
         target: &path::Path,
     ) -> Result<(), error::StagingError>;
+
+    /// Set the Unix permission bits of an already-staged `dest` to `mode`.
+    ///
+    /// The default implementation is a no-op, as are implementations on non-Unix targets.
+    fn set_permissions(
+        &mut self,
+        _dest: &path::Path,
+        _mode: u32,
+    ) -> Result<(), error::StagingError> {
+        Ok(())
+    }
+
+    /// Replicate `src`'s modification time and permission bits onto the already-staged `dest`.
+    ///
+    /// The default implementation is a no-op, which is appropriate for stages that do not touch a
+    /// real filesystem (e.g. a dry-run).
+    fn copy_metadata(
+        &mut self,
+        _dest: &path::Path,
+        _src: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        Ok(())
+    }
 }
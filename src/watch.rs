@@ -0,0 +1,106 @@
+//! Re-stage incrementally as source files change.
+//!
+//! [`watch`] installs a debounced recursive watcher over the source roots of a built
+//! [`builder::Stage`] (or any [`ActionBuilder`]) and re-applies the affected actions whenever a
+//! source changes.  The loop blocks the calling thread and has no cancellation handle; it ends
+//! only when the event channel disconnects.
+
+use std::path;
+use std::sync::mpsc;
+use std::time;
+
+use notify::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use builder::ActionBuilder;
+use error;
+
+/// How long to coalesce filesystem events before re-staging.
+const DEBOUNCE: time::Duration = time::Duration::from_millis(250);
+
+/// The outcome of a single re-stage triggered by a batch of filesystem events.
+#[derive(Debug)]
+pub struct Batch {
+    /// The source paths whose change triggered this re-stage.
+    pub changed: Vec<path::PathBuf>,
+    /// The result of re-applying the affected actions.
+    pub result: Result<(), error::Errors>,
+}
+
+/// Watch the source roots of `stage` and re-stage into `target_dir` on change.
+///
+/// `handler` is invoked once per debounced [`Batch`]; the loop runs until the channel is
+/// disconnected.  A watcher error event is reported to `handler` as a `Batch` with an `Err` result
+/// and does not end the loop.
+pub fn watch<A, H>(
+    stage: A,
+    target_dir: &path::Path,
+    mut handler: H,
+) -> Result<(), error::Errors>
+where
+    A: ActionBuilder,
+    H: FnMut(Batch),
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = Watcher::new(tx, DEBOUNCE)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+    let roots = stage.source_roots();
+    for root in &roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    }
+
+    while let Ok(event) = rx.recv() {
+        let changed = match event {
+            DebouncedEvent::Create(p)
+            | DebouncedEvent::Write(p)
+            | DebouncedEvent::Chmod(p)
+            | DebouncedEvent::Remove(p) => vec![p],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            DebouncedEvent::Error(e, _) => {
+                handler(Batch {
+                    changed: Vec::new(),
+                    result: Err(error::ErrorKind::StagingFailed.error().set_cause(e).into()),
+                });
+                continue;
+            }
+            _ => continue,
+        };
+
+        let result = restage(&stage, target_dir, &changed);
+        handler(Batch { changed, result });
+    }
+
+    Ok(())
+}
+
+/// Re-apply the actions whose source lies under, or contains, one of the `changed` paths, along
+/// with every action that has no source.
+fn restage<A>(
+    stage: &A,
+    target_dir: &path::Path,
+    changed: &[path::PathBuf],
+) -> Result<(), error::Errors>
+where
+    A: ActionBuilder,
+{
+    let actions = stage.build(target_dir)?;
+    for action in &actions {
+        if action_touched(action.source(), changed) {
+            action.perform()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn action_touched(source: Option<&path::Path>, changed: &[path::PathBuf]) -> bool {
+    match source {
+        // Without a known source (e.g. a directory), conservatively re-apply.
+        None => true,
+        Some(source) => changed
+            .iter()
+            .any(|c| source.starts_with(c) || c.starts_with(source)),
+    }
+}